@@ -0,0 +1,390 @@
+//! Arrow IPC and Parquet output and input, with the optional `arrow` feature.
+//!
+//! As output the stations are a single record batch with the columns `name`, `min`, `mean`, `max`, `count` and `sum`.
+//! Temperatures are `Float64` degrees like in the text output, but the mean is not rounded.
+//!
+//! As input the file has a station and a value column, `station` and `value` unless given with `--columns`.
+//! The stations can be any string or binary column, the values any numeric column in degrees. The workers pull
+//! row groups of Parquet files, and record batches of Arrow IPC files, from a shared queue like the chunks of a text file.
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use arrow_array::{
+    cast::AsArray, types::Float64Type, Array, ArrayRef, Float64Array, RecordBatch, StringArray,
+    UInt64Array,
+};
+use arrow_cast::cast;
+use arrow_ipc::{reader::FileReader, writer::FileWriter};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter, ProjectionMask};
+
+use crate::{
+    merge_workers, name_too_long,
+    stats::{RunStats, WorkerStats},
+    table::StationTable,
+    Aggregated, Config, Error, LineEnding, LongLinePolicy, ParseOptions, StationList,
+    WeatherStationStats,
+};
+
+pub fn record_batch(stations: &[(String, WeatherStationStats)]) -> RecordBatch {
+    let schema = Schema::new(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("min", DataType::Float64, false),
+        Field::new("mean", DataType::Float64, false),
+        Field::new("max", DataType::Float64, false),
+        Field::new("count", DataType::UInt64, false),
+        Field::new("sum", DataType::Float64, false),
+    ]);
+    let degrees = |f: fn(&WeatherStationStats) -> i64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(
+            stations.iter().map(|(_, s)| f(s) as f64 / 10.0),
+        ))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            stations.iter().map(|(name, _)| name),
+        )),
+        degrees(|s| s.min),
+        Arc::new(Float64Array::from_iter_values(
+            stations.iter().map(|(_, s)| s.mean()),
+        )),
+        degrees(|s| s.max),
+        Arc::new(UInt64Array::from_iter_values(
+            stations.iter().map(|(_, s)| s.count as u64),
+        )),
+        degrees(|s| s.sum),
+    ];
+    RecordBatch::try_new(Arc::new(schema), columns).unwrap()
+}
+/// The Arrow IPC file format, also known as Feather version 2
+pub fn write_ipc(stations: &[(String, WeatherStationStats)]) -> Vec<u8> {
+    let batch = record_batch(stations);
+    let mut writer = FileWriter::try_new(vec![], &batch.schema()).unwrap();
+    writer.write(&batch).unwrap();
+    writer.into_inner().unwrap()
+}
+pub fn write_parquet(stations: &[(String, WeatherStationStats)]) -> Vec<u8> {
+    let batch = record_batch(stations);
+    let mut writer = ArrowWriter::try_new(vec![], batch.schema(), None).unwrap();
+    writer.write(&batch).unwrap();
+    writer.into_inner().unwrap()
+}
+
+/// Columnar input, recognized by the magic bytes at both ends of the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Parquet,
+    Ipc,
+}
+impl InputFormat {
+    pub fn detect(file_name: &str) -> Option<InputFormat> {
+        let mut file = File::open(file_name).ok()?;
+        let (mut head, mut tail) = ([0; 6], [0; 6]);
+        file.read_exact(&mut head).ok()?;
+        file.seek(SeekFrom::End(-6)).ok()?;
+        file.read_exact(&mut tail).ok()?;
+        if &head == b"ARROW1" && &tail == b"ARROW1" {
+            Some(InputFormat::Ipc)
+        } else if head.starts_with(b"PAR1") && tail.ends_with(b"PAR1") {
+            Some(InputFormat::Parquet)
+        } else {
+            None
+        }
+    }
+}
+
+/// Runs `--threads` workers over the row groups or record batches of the file and merges their results
+pub fn aggregate<T: StationTable>(
+    config: &Config,
+    format: InputFormat,
+    stats: &mut RunStats,
+) -> Result<Aggregated<StationList>, Error> {
+    let path = config.file_name.as_str();
+    let input_error = |error: ArrowError| Error::Input {
+        path: path.to_string(),
+        error: error.to_string(),
+    };
+    stats.bytes = std::fs::metadata(path).unwrap().len() as usize;
+    let units = match format {
+        InputFormat::Parquet => ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .map_err(|e| input_error(e.into()))?
+            .metadata()
+            .num_row_groups(),
+        InputFormat::Ipc => FileReader::try_new(File::open(path).unwrap(), None)
+            .map_err(input_error)?
+            .num_batches(),
+    };
+    let options = config.parse_options(LineEnding::Lf);
+    let next_unit = &AtomicUsize::new(0);
+    let null_rows = &AtomicUsize::new(0);
+    stats.end_phase("chunking");
+    let workers = thread::scope(|s| {
+        let handles = (0..config.threads().min(units))
+            .map(|_| {
+                s.spawn(move || {
+                    let start = Instant::now();
+                    let mut res = Aggregated::<T>::default();
+                    let mut stats = WorkerStats::default();
+                    loop {
+                        let unit = next_unit.fetch_add(1, Ordering::Relaxed);
+                        if unit >= units {
+                            stats.rows = res.rows;
+                            stats.map_size = res.stations.len();
+                            stats.parse_time = start.elapsed();
+                            break Ok((res, stats));
+                        }
+                        for batch in
+                            read_unit(path, format, unit, &config.columns).map_err(input_error)?
+                        {
+                            let batch = batch.map_err(input_error)?;
+                            stats.chunk_lens.push(batch.num_rows());
+                            let nulls =
+                                add_batch(&mut res, &batch, options).map_err(|e| match e {
+                                    BatchError::Arrow(e) => input_error(e),
+                                    BatchError::NameTooLong(e) => e,
+                                })?;
+                            null_rows.fetch_add(nulls, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    let null_rows = null_rows.load(Ordering::Relaxed);
+    if null_rows > 0 {
+        eprintln!("skipped {null_rows} rows with a null station or value");
+    }
+    merge_workers(workers, None, stats)
+}
+/// The batches of one row group or record batch, with only the station and value columns
+fn read_unit(
+    path: &str,
+    format: InputFormat,
+    unit: usize,
+    (station, value): &(String, String),
+) -> Result<Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>, ArrowError> {
+    let file = File::open(path)?;
+    // projections keep the columns in the order of the file
+    let names = (station.clone(), value.clone());
+    let reorder = move |batch: Result<RecordBatch, ArrowError>| {
+        let batch = batch?;
+        let schema = batch.schema();
+        batch.project(&[schema.index_of(&names.0)?, schema.index_of(&names.1)?])
+    };
+    match format {
+        InputFormat::Parquet => {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
+            let schema = builder.schema();
+            let columns = [schema.index_of(station)?, schema.index_of(value)?];
+            let mask = ProjectionMask::roots(builder.parquet_schema(), columns);
+            let reader = builder
+                .with_row_groups(vec![unit])
+                .with_projection(mask)
+                .build()?;
+            Ok(Box::new(reader.map(reorder)))
+        }
+        InputFormat::Ipc => {
+            let schema = FileReader::try_new(File::open(path)?, None)?.schema();
+            let columns = vec![schema.index_of(station)?, schema.index_of(value)?];
+            let mut reader = FileReader::try_new(file, Some(columns))?;
+            reader.set_index(unit)?;
+            Ok(Box::new(reader.take(1).map(reorder)))
+        }
+    }
+}
+enum BatchError {
+    Arrow(ArrowError),
+    NameTooLong(Error),
+}
+impl From<ArrowError> for BatchError {
+    fn from(e: ArrowError) -> Self {
+        BatchError::Arrow(e)
+    }
+}
+/// Adds the rows of a batch with the station and value columns in this order, returns the number of rows with a null
+fn add_batch<T: StationTable>(
+    res: &mut Aggregated<T>,
+    batch: &RecordBatch,
+    options: ParseOptions,
+) -> Result<usize, BatchError> {
+    // names stay bytes like in text input, so they go through the same --invalid-utf8 handling
+    let names = cast(batch.column(0), &DataType::Binary)?;
+    let names = names.as_binary::<i32>();
+    let values = cast(batch.column(1), &DataType::Float64)?;
+    let values = values.as_primitive::<Float64Type>();
+    let mut nulls = 0;
+    for idx in 0..batch.num_rows() {
+        if names.is_null(idx) || values.is_null(idx) || !values.value(idx).is_finite() {
+            nulls += 1;
+            continue;
+        }
+        let name = names.value(idx);
+        if name.len() > options.max_name_len {
+            match options.long_lines {
+                LongLinePolicy::Error => {
+                    return Err(BatchError::NameTooLong(name_too_long(name, options)))
+                }
+                LongLinePolicy::Skip => {
+                    res.skipped_rows += 1;
+                    continue;
+                }
+            }
+        }
+        // to tenths of a degree like the text parsers
+        res.stations
+            .add_measurement(name, (values.value(idx) * 10.0).round() as i64);
+        res.rows += 1;
+    }
+    Ok(nulls)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+    use arrow_ipc::{reader::FileReader, writer::FileWriter};
+    use parquet::{
+        arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+        file::properties::WriterProperties,
+    };
+
+    use super::{record_batch, write_ipc, write_parquet, InputFormat};
+    use crate::{calc, Config, Error, WeatherStationStats};
+
+    #[test]
+    fn writes_ipc_and_parquet() {
+        let stations = [
+            (
+                "Abha".to_string(),
+                WeatherStationStats {
+                    min: -23,
+                    max: 251,
+                    sum: 456,
+                    count: 4,
+                },
+            ),
+            (
+                "Zürich".to_string(),
+                WeatherStationStats {
+                    min: 5,
+                    max: 5,
+                    sum: 5,
+                    count: 1,
+                },
+            ),
+        ];
+        let expected = record_batch(&stations);
+        assert_eq!(expected.num_rows(), 2);
+        let columns = ["name", "min", "mean", "max", "count", "sum"];
+        let schema = expected.schema();
+        assert_eq!(
+            schema.fields().iter().map(|f| f.name()).collect::<Vec<_>>(),
+            columns
+        );
+
+        let ipc = FileReader::try_new(Cursor::new(write_ipc(&stations)), None)
+            .unwrap()
+            .collect::<Result<Vec<RecordBatch>, _>>()
+            .unwrap();
+        assert_eq!(ipc.len(), 1);
+        assert_eq!(ipc[0], expected);
+
+        let path = std::env::temp_dir().join(format!("brc-parquet-{}", std::process::id()));
+        std::fs::write(&path, write_parquet(&stations)).unwrap();
+        let parquet = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<RecordBatch>, _>>()
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(parquet.len(), 1);
+        // parquet keeps the columns but not the exact same schema metadata
+        for (idx, name) in columns.iter().enumerate() {
+            assert_eq!(
+                parquet[0].column_by_name(name).unwrap(),
+                expected.column(idx)
+            );
+        }
+    }
+
+    #[test]
+    fn reads_row_groups_and_batches_in_parallel() {
+        let file_name = "samples/measurements-rounding.txt";
+        let expected = std::fs::read(file_name.replace(".txt", ".out")).unwrap();
+        let input = std::fs::read_to_string(file_name).unwrap();
+        let rows = input
+            .lines()
+            .map(|l| l.rsplit_once(';').unwrap())
+            .collect::<Vec<_>>();
+        // the value column first, to check that the columns are found by name
+        let batches = rows
+            .chunks(1000)
+            .map(|rows| {
+                let values: ArrayRef = Arc::new(Float64Array::from_iter_values(
+                    rows.iter().map(|(_, v)| v.parse::<f64>().unwrap()),
+                ));
+                let names: ArrayRef =
+                    Arc::new(StringArray::from_iter_values(rows.iter().map(|(n, _)| n)));
+                RecordBatch::try_from_iter([("temperature", values), ("station", names)]).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let schema = batches[0].schema();
+
+        let dir = std::env::temp_dir();
+        let parquet = dir.join(format!("brc-input-{}.parquet", std::process::id()));
+        let props = WriterProperties::builder()
+            .set_max_row_group_size(1000)
+            .build();
+        let mut writer = ArrowWriter::try_new(
+            std::fs::File::create(&parquet).unwrap(),
+            schema.clone(),
+            Some(props),
+        )
+        .unwrap();
+        for batch in &batches {
+            writer.write(batch).unwrap();
+        }
+        writer.close().unwrap();
+        let ipc = dir.join(format!("brc-input-{}.arrow", std::process::id()));
+        let mut writer =
+            FileWriter::try_new(std::fs::File::create(&ipc).unwrap(), &schema).unwrap();
+        for batch in &batches {
+            writer.write(batch).unwrap();
+        }
+        writer.finish().unwrap();
+
+        for (path, format) in [(&parquet, InputFormat::Parquet), (&ipc, InputFormat::Ipc)] {
+            let file_name = path.display().to_string();
+            assert_eq!(InputFormat::detect(&file_name), Some(format));
+            let config = Config {
+                file_name,
+                columns: ("station".into(), "temperature".into()),
+                threads: Some(3.try_into().unwrap()),
+                ..Default::default()
+            };
+            assert_eq!(calc(&config).unwrap(), expected, "{format:?}");
+            let missing = calc(&Config {
+                columns: ("station".into(), "value".into()),
+                ..config
+            });
+            assert!(matches!(missing, Err(Error::Input { .. })), "{format:?}");
+        }
+        assert_eq!(InputFormat::detect(file_name), None);
+        std::fs::remove_file(parquet).unwrap();
+        std::fs::remove_file(ipc).unwrap();
+    }
+}
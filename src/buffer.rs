@@ -0,0 +1,93 @@
+//! Sizing and allocating the read buffer of the workers.
+//!
+//! The buffer used to be a fixed 500 kB array on the stack, tuned on a single MacBook.
+//! Now the size follows the input and the machine, and `brc-rs tune` benchmarks the sizes on the current machine.
+use std::ops::{Deref, DerefMut};
+
+/// Smallest buffer allowed, below this the refill and `copy_within` of the partial line start to show up
+pub const MIN_BUF_LEN: usize = 64 * 1024;
+pub const MAX_BUF_LEN: usize = 64 * 1024 * 1024;
+/// Largest buffer picked automatically. The original tuning found no gains above ~500 kB,
+/// and beyond this the buffer of every worker no longer fits in L2 next to the map
+const MAX_AUTO_LEN: usize = 512 * 1024;
+
+pub fn page_size() -> usize {
+    #[cfg(unix)]
+    {
+        // SAFETY: sysconf has no preconditions
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if size > 0 {
+            return size as usize;
+        }
+    }
+    4096
+}
+
+/// Buffer length for reading `file_len` bytes with `threads` workers. Small inputs get a small buffer,
+/// as a worker never needs more than its share of the file, and the length is always a whole number of pages
+pub fn auto_len(file_len: usize, threads: usize, page_size: usize) -> usize {
+    round_to_pages(
+        file_len
+            .div_ceil(threads.max(1))
+            .clamp(MIN_BUF_LEN, MAX_AUTO_LEN),
+        page_size,
+    )
+}
+pub fn round_to_pages(len: usize, page_size: usize) -> usize {
+    len.div_ceil(page_size) * page_size
+}
+
+/// Zeroed heap buffer starting at a page boundary, so each refill touches as few pages as possible
+pub struct AlignedBuf {
+    data: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+impl AlignedBuf {
+    /// `align` must be a power of two
+    pub fn new(len: usize, align: usize) -> AlignedBuf {
+        // over-allocating keeps this safe Rust, at the cost of at most one extra page
+        let data = vec![0; len + align - 1];
+        let offset = data.as_ptr().align_offset(align);
+        AlignedBuf { data, offset, len }
+    }
+}
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.len]
+    }
+}
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auto_len, page_size, AlignedBuf, MAX_AUTO_LEN, MIN_BUF_LEN};
+
+    #[test]
+    fn auto_len_is_whole_pages_within_bounds() {
+        for page_size in [4096, 16384, page_size()] {
+            for (file_len, threads) in [(0, 1), (100, 8), (1_000_000, 3), (13_000_000_000, 16)] {
+                let len = auto_len(file_len, threads, page_size);
+                assert_eq!(len % page_size, 0);
+                assert!((MIN_BUF_LEN..=MAX_AUTO_LEN).contains(&len), "{len}");
+            }
+        }
+        assert_eq!(auto_len(13_000_000_000, 16, 4096), MAX_AUTO_LEN);
+        assert_eq!(auto_len(1_000_000, 4, 4096), 253_952);
+    }
+    #[test]
+    fn aligned_buf_starts_at_page_boundary() {
+        for len in [1, MIN_BUF_LEN, 100_003] {
+            let mut buf = AlignedBuf::new(len, page_size());
+            assert_eq!(buf.len(), len);
+            assert_eq!(buf.as_ptr() as usize % page_size(), 0);
+            buf[len - 1] = 1;
+            assert!(buf[..len - 1].iter().all(|b| *b == 0));
+        }
+    }
+}
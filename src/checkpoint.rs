@@ -0,0 +1,350 @@
+//! Checkpoints of a run in progress, so a crash on slow storage does not lose the chunks that were already done.
+//!
+//! The state file has the chunks of the input and, for every worker, the chunks it has completed with the stations
+//! aggregated from them. The chunks are disjoint, so the part of each worker is consistent on its own,
+//! and the workers never have to stop at the same time for a snapshot.
+//!
+//! It also has the length and the fingerprints of the input, like the `--state` index, and the options the lines
+//! were parsed with. A run only resumes from a checkpoint of the same input parsed the same way.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    incremental,
+    serialize::{invalid_data, read_len, read_stations, read_u64, write_stations, write_u64},
+    table::StationTable,
+    Aggregated, Chunk, Error, ParseOptions, StationList, StationMap,
+};
+
+const MAGIC: &[u8; 8] = b"BRCCKPT2";
+
+/// The input a checkpoint was saved for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Input {
+    pub len: u64,
+    /// Of the first and last bytes, like in the `--state` index
+    pub fingerprints: (u64, u64),
+    /// Line ending, `--max-name-len`, `--long-lines` and `--parser`
+    pub options: [u64; 4],
+}
+impl Input {
+    pub fn new(file: &File, options: ParseOptions) -> io::Result<Input> {
+        let len = file.metadata()?.len();
+        Ok(Input {
+            len,
+            fingerprints: incremental::fingerprints(file, len)?,
+            options: [
+                options.line_ending as u64,
+                options.max_name_len as u64,
+                options.long_lines as u64,
+                options.parser as u64,
+            ],
+        })
+    }
+    fn read(r: &mut impl Read) -> io::Result<Input> {
+        Ok(Input {
+            len: read_u64(r)?,
+            fingerprints: (read_u64(r)?, read_u64(r)?),
+            options: [read_u64(r)?, read_u64(r)?, read_u64(r)?, read_u64(r)?],
+        })
+    }
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        for n in [self.len, self.fingerprints.0, self.fingerprints.1]
+            .iter()
+            .chain(&self.options)
+        {
+            write_u64(w, *n)?;
+        }
+        Ok(())
+    }
+}
+
+/// The chunks completed by one worker, and what they added up to
+#[derive(Default)]
+pub struct Part {
+    /// `start_point` of every completed chunk
+    pub done: Vec<u64>,
+    pub aggregated: Aggregated<StationList>,
+}
+
+pub struct State {
+    pub input: Input,
+    pub chunks: Vec<Chunk>,
+    pub parts: Vec<Part>,
+}
+impl State {
+    pub fn load(path: &Path) -> io::Result<State> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a brc-rs checkpoint"));
+        }
+        let input = Input::read(&mut r)?;
+        let file_len = input.len;
+        let chunks = (0..read_len(&mut r, file_len + 1)?)
+            .map(|_| {
+                Ok(Chunk {
+                    start_point: read_u64(&mut r)?,
+                    len: read_len(&mut r, file_len)?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let parts = (0..read_len(&mut r, u16::MAX as u64)?)
+            .map(|_| {
+                let done = (0..read_len(&mut r, chunks.len() as u64)?)
+                    .map(|_| read_u64(&mut r))
+                    .collect::<io::Result<_>>()?;
+                let skipped_rows = read_len(&mut r, u64::MAX)?;
+                let rows = read_len(&mut r, u64::MAX)?;
+                Ok(Part {
+                    done,
+                    aggregated: Aggregated {
+                        stations: read_stations(&mut r)?,
+                        skipped_rows,
+                        rows,
+                    },
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(State {
+            input,
+            chunks,
+            parts,
+        })
+    }
+    /// Fails unless the checkpoint was saved for the same input, parsed with the same options
+    pub fn check(&self, input: &Input) -> io::Result<()> {
+        if self.input.len != input.len {
+            return Err(invalid_data(format!(
+                "saved for an input of {} bytes, the input has {} bytes",
+                self.input.len, input.len
+            )));
+        }
+        if self.input.fingerprints != input.fingerprints {
+            return Err(invalid_data(
+                "saved for a different input of the same length",
+            ));
+        }
+        if self.input.options != input.options {
+            return Err(invalid_data(
+                "saved with a different line ending, --max-name-len, --long-lines or --parser",
+            ));
+        }
+        Ok(())
+    }
+    /// The chunks that no worker completed
+    pub fn pending(&self) -> Vec<Chunk> {
+        let done = self
+            .parts
+            .iter()
+            .flat_map(|p| &p.done)
+            .collect::<std::collections::HashSet<_>>();
+        self.chunks
+            .iter()
+            .filter(|c| !done.contains(&c.start_point))
+            .copied()
+            .collect()
+    }
+    /// Merges the parts of all workers into one
+    pub fn into_part(self) -> Part {
+        let mut res = Part::default();
+        let mut stations = StationMap::default();
+        for part in self.parts {
+            res.done.extend(part.done);
+            res.aggregated.skipped_rows += part.aggregated.skipped_rows;
+            res.aggregated.rows += part.aggregated.rows;
+            for (name, stats) in part.aggregated.stations {
+                stations.add_stats(name, stats);
+            }
+        }
+        res.aggregated.stations = stations.into_stations().collect();
+        res
+    }
+}
+fn write_state(
+    w: &mut impl Write,
+    input: &Input,
+    chunks: &[Chunk],
+    parts: &[&Part],
+) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    input.write(w)?;
+    write_u64(w, chunks.len() as u64)?;
+    for c in chunks {
+        write_u64(w, c.start_point)?;
+        write_u64(w, c.len as u64)?;
+    }
+    write_u64(w, parts.len() as u64)?;
+    for part in parts {
+        write_u64(w, part.done.len() as u64)?;
+        for start in &part.done {
+            write_u64(w, *start)?;
+        }
+        write_u64(w, part.aggregated.skipped_rows as u64)?;
+        write_u64(w, part.aggregated.rows as u64)?;
+        write_stations(w, part.aggregated.stations.iter().map(|(k, v)| (&k[..], v)))?;
+    }
+    Ok(())
+}
+
+/// Writes the state file for the workers of a run
+pub struct Checkpointer {
+    path: PathBuf,
+    pub interval: Duration,
+    input: Input,
+    /// Every chunk of the input, including the ones completed before resuming
+    chunks: Vec<Chunk>,
+    /// Everything completed before resuming, empty for a fresh run
+    pub resumed: Part,
+    /// Latest part saved by each worker
+    workers: Mutex<Vec<Part>>,
+}
+impl Checkpointer {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        interval: Duration,
+        input: Input,
+        chunks: Vec<Chunk>,
+        resumed: Part,
+        workers: usize,
+    ) -> Checkpointer {
+        Checkpointer {
+            path: path.into(),
+            interval,
+            input,
+            chunks,
+            resumed,
+            workers: Mutex::new((0..workers).map(|_| Part::default()).collect()),
+        }
+    }
+    /// Replaces the part of the worker and writes the state file. The file is written next to the old one
+    /// and renamed over it, so a crash while writing leaves the previous checkpoint intact
+    pub fn save(&self, worker: usize, part: Part) -> Result<(), Error> {
+        let mut workers = self.workers.lock().unwrap();
+        workers[worker] = part;
+        let parts = std::iter::once(&self.resumed)
+            .chain(workers.iter())
+            .collect::<Vec<_>>();
+        let tmp = self.path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp).map_err(|e| self.error(e))?);
+        write_state(&mut w, &self.input, &self.chunks, &parts)
+            .and_then(|_| w.into_inner().map_err(|e| e.into_error()))
+            .and_then(|f| f.sync_all())
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .map_err(|e| self.error(e))
+    }
+    /// Removes the state file once the run is complete
+    pub fn remove(&self) -> Result<(), Error> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(self.error(e)),
+            _ => Ok(()),
+        }
+    }
+    pub fn error(&self, error: io::Error) -> Error {
+        Error::Checkpoint {
+            path: self.path.display().to_string(),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Checkpointer, Input, Part, State};
+    use crate::{Aggregated, Chunk, WeatherStationStats};
+
+    fn part(done: Vec<u64>, name: &[u8], count: usize) -> Part {
+        Part {
+            done,
+            aggregated: Aggregated {
+                stations: vec![(
+                    name.to_vec(),
+                    WeatherStationStats {
+                        min: -10,
+                        max: 10,
+                        sum: 5,
+                        count,
+                    },
+                )],
+                skipped_rows: 1,
+                rows: count,
+            },
+        }
+    }
+
+    #[test]
+    fn checkpoint_keeps_resumed_and_worker_parts() {
+        let path = std::env::temp_dir().join(format!("brc-checkpoint-{}", std::process::id()));
+        let chunks = (0..4)
+            .map(|i| Chunk {
+                start_point: i * 10,
+                len: 10,
+            })
+            .collect::<Vec<_>>();
+        let input = Input {
+            len: 40,
+            fingerprints: (1, 2),
+            options: [0, 100, 0, 0],
+        };
+        let checkpointer = Checkpointer::new(
+            &path,
+            Duration::ZERO,
+            input,
+            chunks.clone(),
+            part(vec![0], b"a", 2),
+            2,
+        );
+        checkpointer.save(1, part(vec![20], b"b", 3)).unwrap();
+        checkpointer.save(1, part(vec![20, 30], b"a", 4)).unwrap();
+
+        let state = State::load(&path).unwrap();
+        assert_eq!(state.input, input);
+        state.check(&input).unwrap();
+        for other in [
+            Input { len: 41, ..input },
+            Input {
+                fingerprints: (1, 3),
+                ..input
+            },
+            Input {
+                options: [0, 200, 0, 0],
+                ..input
+            },
+        ] {
+            assert!(state.check(&other).is_err(), "{other:?}");
+        }
+        assert_eq!(state.chunks, chunks);
+        assert_eq!(state.pending(), vec![chunks[1]]);
+        let mut merged = state.into_part();
+        merged.done.sort_unstable();
+        assert_eq!(merged.done, vec![0, 20, 30]);
+        assert_eq!(merged.aggregated.rows, 6);
+        assert_eq!(merged.aggregated.skipped_rows, 2);
+        assert_eq!(
+            merged.aggregated.stations,
+            vec![(
+                b"a".to_vec(),
+                WeatherStationStats {
+                    min: -10,
+                    max: 10,
+                    sum: 10,
+                    count: 6,
+                }
+            )]
+        );
+        checkpointer.remove().unwrap();
+        assert!(!path.exists());
+    }
+    #[test]
+    fn other_files_are_not_checkpoints() {
+        assert!(State::load("samples/measurements-3.txt".as_ref()).is_err());
+    }
+}
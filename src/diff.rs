@@ -0,0 +1,284 @@
+//! `brc-rs diff`: compares two results files, in the text format like the `.out` files in `samples/` or written with
+//! `--format json|binary`.
+//!
+//! Stations only in one of the files are listed, and the min, mean and max that differ by more than `--tolerance`
+//! degrees. Counts are only compared when both files have them. The text format rounds the mean to one decimal, so
+//! when either file is text the mean of the other is rounded the same way before comparing.
+use std::{collections::BTreeMap, fmt::Write, io};
+
+use crate::{
+    serialize,
+    text::{self, TextStats},
+    usage_with_code, Error, WeatherStationStats,
+};
+
+/// A station in either format, temperatures in degrees
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Row {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    /// `None` in the text format
+    pub count: Option<usize>,
+}
+impl From<&WeatherStationStats> for Row {
+    fn from(stats: &WeatherStationStats) -> Row {
+        Row {
+            min: stats.min as f64 / 10.0,
+            mean: stats.mean(),
+            max: stats.max as f64 / 10.0,
+            count: Some(stats.count),
+        }
+    }
+}
+
+impl From<&TextStats> for Row {
+    fn from(stats: &TextStats) -> Row {
+        Row {
+            min: stats.min as f64 / 10.0,
+            mean: stats.mean as f64 / 10.0,
+            max: stats.max as f64 / 10.0,
+            count: None,
+        }
+    }
+}
+
+/// Reads results written with `--format text`, `json` or `binary`
+pub fn read(bytes: &[u8]) -> io::Result<Vec<(String, Row)>> {
+    match serialize::read_results(bytes) {
+        Ok(stations) => Ok(stations
+            .iter()
+            .map(|(name, stats)| (name.clone(), Row::from(stats)))
+            .collect()),
+        Err(error) => text::parse(bytes)
+            .map(|stations| {
+                stations
+                    .iter()
+                    .map(|(name, stats)| (name.clone(), Row::from(stats)))
+                    .collect()
+            })
+            // the error of the format it looks like, text can only start with a quote if a name does
+            .map_err(|text_error| {
+                if bytes.starts_with(b"{") && !bytes.starts_with(b"{\"") {
+                    text_error
+                } else {
+                    error
+                }
+            }),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    /// Only in the first file
+    Removed(String),
+    /// Only in the second file
+    Added(String),
+    Changed {
+        station: String,
+        field: &'static str,
+        from: f64,
+        to: f64,
+    },
+}
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Removed(station) => write!(f, "- {station}"),
+            Difference::Added(station) => write!(f, "+ {station}"),
+            Difference::Changed {
+                station,
+                field,
+                from,
+                to,
+            } => {
+                // temperatures with one decimal like the results, counts are whole
+                let decimals = if *field == "count" { 0 } else { 1 };
+                write!(
+                    f,
+                    "~ {station}: {field} {from:.decimals$} -> {to:.decimals$} ({:+.decimals$})",
+                    to - from
+                )
+            }
+        }
+    }
+}
+
+/// The differences from `a` to `b`, in the order of the station names
+pub fn compare(a: &[(String, Row)], b: &[(String, Row)], tolerance: f64) -> Vec<Difference> {
+    let a = a.iter().map(|(k, v)| (k, v)).collect::<BTreeMap<_, _>>();
+    let b = b.iter().map(|(k, v)| (k, v)).collect::<BTreeMap<_, _>>();
+    let mut res = vec![];
+    for (&station, &a_row) in &a {
+        let Some(&b_row) = b.get(station) else {
+            res.push(Difference::Removed(station.clone()));
+            continue;
+        };
+        let (mut a_mean, mut b_mean) = (a_row.mean, b_row.mean);
+        if a_row.count.is_none() || b_row.count.is_none() {
+            a_mean = round_like_text(a_mean);
+            b_mean = round_like_text(b_mean);
+        }
+        let mut fields = vec![
+            ("min", a_row.min, b_row.min, tolerance),
+            ("mean", a_mean, b_mean, tolerance),
+            ("max", a_row.max, b_row.max, tolerance),
+        ];
+        if let (Some(a_count), Some(b_count)) = (a_row.count, b_row.count) {
+            fields.push(("count", a_count as f64, b_count as f64, 0.0));
+        }
+        for (field, from, to, tolerance) in fields {
+            // a little slack for values that only differ in their float representation
+            if (to - from).abs() > tolerance + 1e-9 {
+                res.push(Difference::Changed {
+                    station: station.clone(),
+                    field,
+                    from,
+                    to,
+                });
+            }
+        }
+    }
+    for &station in b.keys() {
+        if !a.contains_key(station) {
+            res.push(Difference::Added(station.clone()));
+        }
+    }
+    res.sort_by(|x, y| name(x).cmp(name(y)));
+    res
+}
+fn name(difference: &Difference) -> &str {
+    match difference {
+        Difference::Removed(station)
+        | Difference::Added(station)
+        | Difference::Changed { station, .. } => station,
+    }
+}
+/// The mean as the text format writes it
+fn round_like_text(mean: f64) -> f64 {
+    format!("{mean:.1}").parse().unwrap()
+}
+
+/// The differences as lines, empty if there are none
+pub fn diff(mut args: impl Iterator<Item = String>) -> Result<Vec<u8>, Error> {
+    let mut tolerance = 0.0;
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tolerance" => {
+                tolerance = args
+                    .next()
+                    .and_then(|t| t.parse::<f64>().ok())
+                    .filter(|t| *t >= 0.0)
+                    .unwrap_or_else(|| {
+                        usage_with_code("--tolerance expects degrees, at least 0", 2)
+                    })
+            }
+            flag if flag.starts_with("--") => {
+                usage_with_code(&format!("unknown option for diff {flag}"), 2)
+            }
+            _ => files.push(arg),
+        }
+    }
+    let [a, b] = &files[..] else {
+        usage_with_code("diff expects two files", 2);
+    };
+    let read_file = |path: &String| {
+        std::fs::read(path)
+            .and_then(|bytes| read(&bytes))
+            .map_err(|error| Error::Results {
+                path: path.clone(),
+                error,
+            })
+    };
+    let mut res = String::new();
+    for difference in compare(&read_file(a)?, &read_file(b)?, tolerance) {
+        writeln!(res, "{difference}").unwrap();
+    }
+    Ok(res.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, diff, read, Difference};
+    use crate::{serialize, Error, WeatherStationStats};
+
+    #[test]
+    fn compares_text_and_json() {
+        let text = read(b"{Abha=-1.0/11.4/25.1, Oslo=1.0/2.0/3.0}\n").unwrap();
+        let json = read(&serialize::write_json(&[
+            (
+                "Abha".into(),
+                WeatherStationStats {
+                    min: -10,
+                    max: 251,
+                    sum: 456,
+                    count: 4,
+                },
+            ),
+            (
+                "Zagreb".into(),
+                WeatherStationStats {
+                    min: 0,
+                    max: 0,
+                    sum: 0,
+                    count: 1,
+                },
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(compare(&text, &text, 0.0), []);
+        assert_eq!(
+            compare(&text, &json, 0.0),
+            [
+                Difference::Removed("Oslo".into()),
+                Difference::Added("Zagreb".into())
+            ]
+        );
+        let moved = read(b"{Abha=-1.1/11.4/25.1, Oslo=1.0/2.1/3.0}\n").unwrap();
+        assert_eq!(compare(&text, &moved, 0.1), []);
+        let differences = compare(&text, &moved, 0.0);
+        assert_eq!(
+            differences
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "~ Abha: min -1.0 -> -1.1 (-0.1)",
+                "~ Oslo: mean 2.0 -> 2.1 (+0.1)"
+            ]
+        );
+        let count = Difference::Changed {
+            station: "Abha".into(),
+            field: "count",
+            from: 4.0,
+            to: 5.0,
+        };
+        assert_eq!(count.to_string(), "~ Abha: count 4 -> 5 (+1)");
+        assert!(read(b"{Abha=1.0/1.0}\n").is_err());
+        assert_eq!(read(b"{}\n").unwrap(), []);
+        let quoted = read(b"{\"Washington, D.C.\"=1.0/1.0/1.0}\n").unwrap();
+        assert_eq!(quoted[0].0, "\"Washington, D.C.\"");
+    }
+
+    #[test]
+    fn returns_the_report() {
+        let args = |a: &str, b: &str| [a.to_string(), b.to_string()].into_iter();
+        let same = diff(args(
+            "samples/measurements-3.out",
+            "samples/measurements-3.out",
+        ))
+        .unwrap();
+        assert!(same.is_empty());
+        let report = diff(args(
+            "samples/measurements-3.out",
+            "samples/measurements-10.out",
+        ))
+        .unwrap();
+        assert!(String::from_utf8(report).unwrap().contains("- Bosaso\n"));
+        assert!(matches!(
+            diff(args("samples/measurements-3.out", "samples/missing.out")),
+            Err(Error::Results { .. })
+        ));
+    }
+}
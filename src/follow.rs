@@ -0,0 +1,323 @@
+//! `brc-rs follow`: keeps aggregating a file that is appended to, like `tail -f`.
+//!
+//! Only complete lines are parsed, a partially written last line is left for the next poll. If the file is truncated
+//! or replaced with a new file (log rotation), the new contents are read from the start, keeping the stations so far.
+//! A truncation is noticed by the file getting shorter than what has been read, so it has to be polled before the new
+//! contents grow past the old length. A rotation is noticed by the device and inode on unix. Elsewhere it is only
+//! noticed like a truncation, and the lines appended to the old file since the last poll are lost.
+use std::{
+    fs::{File, Metadata},
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    aggregate_measurements, buffer::MIN_BUF_LEN, names, output, scan::Swar, table::StationTable,
+    Aggregated, Config, Error, LineEnding, ParseOptions, WeatherStationStats,
+};
+
+/// How often the file is checked for new bytes
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Follower {
+    path: String,
+    file: Option<File>,
+    /// Device and inode of `file` on unix, to notice when the path points to a new file
+    identity: Option<(u64, u64)>,
+    /// Offset after the last complete line that has been aggregated
+    pos: u64,
+    /// Detected from the first bytes of the first file, if not given on the command line
+    line_ending: Option<LineEnding>,
+    options: ParseOptions,
+    buf: Vec<u8>,
+    pub aggregated: Aggregated,
+}
+impl Follower {
+    pub fn new(config: &Config) -> Follower {
+        Follower {
+            path: config.file_name.clone(),
+            file: None,
+            identity: None,
+            pos: 0,
+            line_ending: config.line_ending,
+            options: config.parse_options(LineEnding::Lf),
+            buf: vec![0; MIN_BUF_LEN.max(config.max_name_len + 8)],
+            aggregated: Aggregated::default(),
+        }
+    }
+    /// Aggregates the lines completed since the last poll, returns whether there were any
+    pub fn poll(&mut self) -> Result<bool, Error> {
+        let meta = match std::fs::metadata(&self.path) {
+            Ok(meta) => meta,
+            // between moving the old file away and creating the new one
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => {
+                return Err(Error::Read {
+                    path: self.path.clone(),
+                    error,
+                })
+            }
+        };
+        let mut changed = false;
+        if self.file.is_some() && identity(&meta) != self.identity {
+            // rotated: whatever was appended to the old file before the move is still readable through the old handle
+            changed |= self.read_complete_lines(None)?;
+            self.file = None;
+        } else if self.file.is_some() && meta.len() < self.pos {
+            // truncated in place, or rotated where the identity is not known, the new contents start from the beginning
+            self.file = None;
+        }
+        if self.file.is_none() {
+            let Ok(file) = File::open(&self.path) else {
+                return Ok(changed);
+            };
+            self.identity = file.metadata().ok().as_ref().and_then(identity);
+            self.file = Some(file);
+            self.pos = 0;
+        }
+        changed |= self.read_complete_lines(Some(meta.len()))?;
+        Ok(changed)
+    }
+    /// Aggregates the complete lines from `pos` up to `len`, or up to the current end of the file
+    fn read_complete_lines(&mut self, len: Option<u64>) -> Result<bool, Error> {
+        let read_error = |error| Error::Read {
+            path: self.path.clone(),
+            error,
+        };
+        let file = self.file.as_mut().unwrap();
+        let len = match len {
+            Some(len) => len,
+            None => file.metadata().map_err(read_error)?.len(),
+        };
+        if len <= self.pos {
+            return Ok(false);
+        }
+        if self.line_ending.is_none() {
+            file.seek(SeekFrom::Start(0)).map_err(read_error)?;
+            let line_ending = LineEnding::detect(
+                io::BufReader::new(&mut *file)
+                    .fill_buf()
+                    .map_err(read_error)?,
+            );
+            self.line_ending = Some(line_ending);
+            self.options.line_ending = line_ending;
+        }
+        let end = complete_end(file, self.pos, len).map_err(read_error)?;
+        if end == self.pos {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::Start(self.pos)).map_err(read_error)?;
+        aggregate_measurements(
+            &mut self.aggregated,
+            (&mut *file).take(end - self.pos),
+            &self.path,
+            &mut self.buf,
+            self.options,
+            Swar,
+            None,
+        )?;
+        self.pos = end;
+        Ok(true)
+    }
+    /// The stations so far, sorted by name
+    pub fn stations(&self, config: &Config) -> Result<Vec<(String, WeatherStationStats)>, Error> {
+        let mut stations = names::resolve(
+            self.aggregated.stations.snapshot(),
+            config.invalid_utf8,
+            config.nfc,
+        )?
+        .stations;
+        stations.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(stations)
+    }
+    pub fn render(&self, config: &Config) -> Result<Vec<u8>, Error> {
+        Ok(output::render(config.format, &self.stations(config)?))
+    }
+}
+#[cfg(unix)]
+fn identity(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+#[cfg(not(unix))]
+fn identity(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+/// Offset right after the last newline between `start` and `end`, or `start` if there is none.
+/// Searches backwards from the end, so a big append does not have to be read twice
+fn complete_end(file: &mut File, start: u64, end: u64) -> io::Result<u64> {
+    let mut block = [0; 4096];
+    let mut block_end = end;
+    while block_end > start {
+        let block_start = block_end.saturating_sub(block.len() as u64).max(start);
+        let block = &mut block[..(block_end - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(block)?;
+        if let Some(idx) = block.iter().rposition(|b| *b == b'\n') {
+            return Ok(block_start + idx as u64 + 1);
+        }
+        block_end = block_start;
+    }
+    Ok(start)
+}
+
+/// Follows the file until the process is stopped, writing the results every `--emit-interval`
+/// and whenever a line is entered on stdin
+pub fn follow(config: &Config) -> Result<Vec<u8>, Error> {
+    let requested = Arc::new(AtomicBool::new(false));
+    {
+        let requested = requested.clone();
+        thread::spawn(move || {
+            for _ in io::stdin().lock().lines().map_while(Result::ok) {
+                requested.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+    let mut follower = Follower::new(config);
+    let mut last_emit = Instant::now();
+    let mut changed_since_emit = false;
+    loop {
+        changed_since_emit |= follower.poll()?;
+        let interval_passed = !config.emit_interval.is_zero()
+            && last_emit.elapsed() >= config.emit_interval
+            && changed_since_emit;
+        if interval_passed || requested.swap(false, Ordering::Relaxed) {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&follower.render(config)?).unwrap();
+            stdout.flush().unwrap();
+            if follower.aggregated.skipped_rows > 0 {
+                eprintln!(
+                    "skipped {} rows with a station name longer than {} bytes",
+                    follower.aggregated.skipped_rows, config.max_name_len
+                );
+            }
+            last_emit = Instant::now();
+            changed_since_emit = false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+    use super::Follower;
+    use crate::{Config, Error, LongLinePolicy};
+
+    fn append(path: &PathBuf, bytes: &[u8]) {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap()
+            .write_all(bytes)
+            .unwrap();
+    }
+    fn results(follower: &Follower, config: &Config) -> String {
+        String::from_utf8(follower.render(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn follows_appends_truncation_and_rotation() {
+        let path = std::env::temp_dir().join(format!("brc-follow-{}", std::process::id()));
+        let config = Config {
+            file_name: path.display().to_string(),
+            ..Default::default()
+        };
+        let mut follower = Follower::new(&config);
+        // nothing to read before the file exists
+        assert!(!follower.poll().unwrap());
+
+        append(&path, b"a;1.0\nb;2.0\nc;3");
+        assert!(follower.poll().unwrap());
+        assert_eq!(
+            results(&follower, &config),
+            "{a=1.0/1.0/1.0, b=2.0/2.0/2.0}\n"
+        );
+        assert!(!follower.poll().unwrap());
+        // the partial line is only parsed once it is complete
+        append(&path, b".0\na;3.0\n");
+        assert!(follower.poll().unwrap());
+        assert_eq!(
+            results(&follower, &config),
+            "{a=1.0/2.0/3.0, b=2.0/2.0/2.0, c=3.0/3.0/3.0}\n"
+        );
+
+        std::fs::write(&path, b"b;-2.0\n").unwrap();
+        assert!(follower.poll().unwrap());
+        assert_eq!(
+            results(&follower, &config),
+            "{a=1.0/2.0/3.0, b=-2.0/0.0/2.0, c=3.0/3.0/3.0}\n"
+        );
+
+        // rotated: on unix the line appended to the old file right before the move is not lost
+        append(&path, b"d;4.0\n");
+        let rotated = path.with_extension("1");
+        std::fs::rename(&path, &rotated).unwrap();
+        assert!(!follower.poll().unwrap());
+        append(&path, b"e;5.0\n");
+        follower.poll().unwrap();
+        let d = if cfg!(unix) { "d=4.0/4.0/4.0, " } else { "" };
+        assert_eq!(
+            results(&follower, &config),
+            format!("{{a=1.0/2.0/3.0, b=-2.0/0.0/2.0, c=3.0/3.0/3.0, {d}e=5.0/5.0/5.0}}\n")
+        );
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(rotated).unwrap();
+    }
+
+    #[test]
+    fn returns_read_errors_and_counts_skipped_rows() {
+        let path = std::env::temp_dir().join(format!("brc-follow-skip-{}", std::process::id()));
+        std::fs::write(&path, b"a;1.0\nlong name;2.0\n").unwrap();
+        let config = Config {
+            file_name: path.display().to_string(),
+            max_name_len: 4,
+            long_lines: LongLinePolicy::Skip,
+            ..Default::default()
+        };
+        let mut follower = Follower::new(&config);
+        assert!(follower.poll().unwrap());
+        assert_eq!(results(&follower, &config), "{a=1.0/1.0/1.0}\n");
+        assert_eq!(follower.aggregated.skipped_rows, 1);
+
+        // a file in place of a directory is not a missing file that might still appear
+        let mut follower = Follower::new(&Config {
+            file_name: path.join("x").display().to_string(),
+            ..Default::default()
+        });
+        assert!(matches!(follower.poll(), Err(Error::Read { .. })));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// A directory can be opened like a file on unix, but reading it fails
+    #[cfg(unix)]
+    #[test]
+    fn returns_failed_reads() {
+        use crate::LineEnding;
+
+        let dir = std::env::temp_dir().join(format!("brc-follow-dir-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        // so the directory is not empty, and has a length to read up to
+        append(&dir.join("a"), b"a;1.0\n");
+        for line_ending in [None, Some(LineEnding::Lf)] {
+            let mut follower = Follower::new(&Config {
+                file_name: dir.display().to_string(),
+                line_ending,
+                ..Default::default()
+            });
+            let res = follower.poll();
+            assert!(
+                matches!(&res, Err(Error::Read { error, .. }) if error.raw_os_error() == Some(libc::EISDIR)),
+                "{res:?}"
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
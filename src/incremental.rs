@@ -0,0 +1,140 @@
+//! `--state`: keeps the stations of a run, so the next run over the same file only aggregates what was appended.
+//!
+//! Next to the stations, the state has the length of the file at the time and fingerprints of the first and last bytes
+//! before that length. If the file is at least as long and the fingerprints still match, only the rest is read.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    serialize::{invalid_data, read_len, read_stations, read_u64, write_stations, write_u64},
+    Aggregated, StationList,
+};
+
+const MAGIC: &[u8; 8] = b"BRCIDX1\n";
+/// Bytes of the head and the tail that are fingerprinted. Appending loggers never rewrite earlier bytes,
+/// so this is about noticing a different file, not about proving that every byte is unchanged
+const FINGERPRINT_LEN: u64 = 4096;
+
+pub struct Index {
+    /// Length of the file when the state was saved, always right after a newline
+    pub len: u64,
+    fingerprints: (u64, u64),
+    pub aggregated: Aggregated<StationList>,
+}
+impl Index {
+    pub fn load(path: &Path) -> io::Result<Index> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a brc-rs state file"));
+        }
+        Ok(Index {
+            len: read_u64(&mut r)?,
+            fingerprints: (read_u64(&mut r)?, read_u64(&mut r)?),
+            aggregated: Aggregated {
+                skipped_rows: read_len(&mut r, u64::MAX)?,
+                rows: read_len(&mut r, u64::MAX)?,
+                stations: read_stations(&mut r)?,
+            },
+        })
+    }
+    /// Whether the first `len` bytes of the input are still the ones the state was saved for
+    pub fn matches(&self, input: &str) -> io::Result<bool> {
+        let file = File::open(input)?;
+        if file.metadata()?.len() < self.len {
+            return Ok(false);
+        }
+        Ok(fingerprints(&file, self.len)? == self.fingerprints)
+    }
+    /// Saves the stations of the first `len` bytes of the input. Returns `false` without saving if the input
+    /// does not end with a newline, as the last line might still be in the middle of being written
+    pub fn save(
+        path: &Path,
+        input: &str,
+        len: u64,
+        aggregated: &Aggregated<StationList>,
+    ) -> io::Result<bool> {
+        let file = File::open(input)?;
+        let mut last = [0];
+        if len > 0 && (read_exact_at(&file, &mut last, len - 1).is_err() || last != *b"\n") {
+            return Ok(false);
+        }
+        let (head, tail) = fingerprints(&file, len)?;
+        let tmp = path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(MAGIC)?;
+        for n in [len, head, tail] {
+            write_u64(&mut w, n)?;
+        }
+        write_u64(&mut w, aggregated.skipped_rows as u64)?;
+        write_u64(&mut w, aggregated.rows as u64)?;
+        write_stations(&mut w, aggregated.stations.iter().map(|(k, v)| (&k[..], v)))?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(true)
+    }
+}
+/// Fingerprints of the first and the last bytes before `len`
+pub fn fingerprints(file: &File, len: u64) -> io::Result<(u64, u64)> {
+    let n = len.min(FINGERPRINT_LEN);
+    let mut buf = vec![0; n as usize];
+    read_exact_at(file, &mut buf, 0)?;
+    let head = fnv1a(&buf);
+    read_exact_at(file, &mut buf, len - n)?;
+    Ok((head, fnv1a(&buf)))
+}
+/// Like `FileExt::read_exact_at`, which is unix only
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Index;
+    use crate::Aggregated;
+
+    #[test]
+    fn state_only_matches_appended_file() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("brc-index-input-{}", std::process::id()));
+        let path = dir.join(format!("brc-index-{}", std::process::id()));
+        let input_name = input.display().to_string();
+        let prefix = "a;1.0\n".repeat(1000);
+        std::fs::write(&input, &prefix).unwrap();
+        let aggregated = Aggregated {
+            stations: vec![],
+            skipped_rows: 1,
+            rows: 1000,
+        };
+        assert!(Index::save(&path, &input_name, prefix.len() as u64, &aggregated).unwrap());
+        let index = Index::load(&path).unwrap();
+        assert_eq!(index.len, prefix.len() as u64);
+        assert_eq!(index.aggregated.rows, 1000);
+        assert!(index.matches(&input_name).unwrap());
+
+        std::fs::write(&input, prefix.clone() + "b;2.0\n").unwrap();
+        assert!(index.matches(&input_name).unwrap());
+        // a different file of the same length
+        std::fs::write(&input, prefix.replace("a;1.0", "c;1.0")).unwrap();
+        assert!(!index.matches(&input_name).unwrap());
+        // truncated
+        std::fs::write(&input, &prefix[6..]).unwrap();
+        assert!(!index.matches(&input_name).unwrap());
+        // a partial last line is not saved
+        std::fs::write(&input, prefix + "b;2").unwrap();
+        assert!(!Index::save(&path, &input_name, 6003, &aggregated).unwrap());
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
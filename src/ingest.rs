@@ -0,0 +1,369 @@
+//! `brc-rs ingest`: aggregates `station;value` lines that sensors push over TCP or UDP, instead of reading a file.
+//!
+//! TCP is a stream of lines, one connection per sensor or shared by many. Every UDP datagram has one or more
+//! complete lines, the last newline can be left out. Each connection parses what it has received into a small map of
+//! its own and adds it to the shared stations a batch at a time. The stations are split into shards by name,
+//! so connections only wait for each other when their batches have stations of the same shard.
+//!
+//! Lines come from the network, so they are checked before `parse_line`, which expects well formed input.
+//! Malformed lines and names longer than `--max-name-len` are skipped and counted. The results are served
+//! with the same HTTP API as `brc-rs serve` on `--listen`.
+use std::{
+    hash::BuildHasher,
+    io::{self, Read},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    buffer::MIN_BUF_LEN,
+    names::{self, InvalidUtf8Policy},
+    parse_line,
+    serve::{self, Snapshot},
+    table::StationTable,
+    usage, Aggregated, BuildCustomHasher, Config, Error, ParseOptions,
+};
+
+/// Longest UDP payload
+const MAX_DATAGRAM_LEN: usize = 65536;
+
+/// Wait before trying again after `accept` or `recv` fails, doubling up to a second while it keeps failing.
+/// Errors like running out of file descriptors do not go away by retrying right away
+struct Backoff(Duration);
+impl Backoff {
+    const MIN: Duration = Duration::from_millis(10);
+    const MAX: Duration = Duration::from_secs(1);
+    fn new() -> Backoff {
+        Backoff(Backoff::MIN)
+    }
+    fn failed(&mut self) {
+        thread::sleep(self.0);
+        self.0 = (self.0 * 2).min(Backoff::MAX);
+    }
+    fn reset(&mut self) {
+        self.0 = Backoff::MIN;
+    }
+}
+
+/// The stations of every line received so far
+pub struct Shards {
+    shards: Vec<Mutex<Aggregated>>,
+    hasher: BuildCustomHasher,
+}
+impl Shards {
+    pub fn new(count: usize) -> Shards {
+        Shards {
+            shards: (0..count).map(|_| Mutex::default()).collect(),
+            hasher: BuildCustomHasher::default(),
+        }
+    }
+    fn shard(&self, name: &[u8]) -> usize {
+        // the high bits, as the low bits of the hash are the weakest
+        ((self.hasher.hash_one(name) as u128 * self.shards.len() as u128) >> 64) as usize
+    }
+    /// Adds a batch of lines aggregated by a connection, locking each shard at most once
+    pub fn add(&self, batch: Aggregated) {
+        let mut by_shard = vec![vec![]; self.shards.len()];
+        for (name, stats) in batch.stations {
+            by_shard[self.shard(&name)].push((name, stats));
+        }
+        // the row counts go to the first shard, they are only ever summed
+        for (idx, stations) in by_shard.into_iter().enumerate() {
+            if stations.is_empty() && idx > 0 {
+                continue;
+            }
+            let mut shard = self.shards[idx].lock().unwrap();
+            if idx == 0 {
+                shard.rows += batch.rows;
+                shard.skipped_rows += batch.skipped_rows;
+            }
+            for (name, stats) in stations {
+                shard.stations.add_stats(name, stats);
+            }
+        }
+    }
+    /// The stations so far, resolved and sorted like the results of a file
+    pub fn snapshot(&self, config: &Config) -> Snapshot {
+        let mut stations = vec![];
+        let mut rows = 0;
+        let mut skipped = 0;
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stations.extend(shard.stations.snapshot());
+            rows += shard.rows;
+            skipped += shard.skipped_rows;
+        }
+        // names that are not valid UTF-8 are already skipped when received if the policy is reject
+        let mut stations = names::resolve(stations, config.invalid_utf8, config.nfc)
+            .unwrap()
+            .stations;
+        stations.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Snapshot {
+            stations,
+            rows,
+            skipped,
+        }
+    }
+}
+
+/// Aggregates the complete lines of `bytes` into `batch`. Lines that cannot be parsed are counted as skipped
+fn add_lines(
+    batch: &mut Aggregated,
+    bytes: &[u8],
+    options: ParseOptions,
+    reject_invalid_utf8: bool,
+) {
+    for line in bytes.split(|b| *b == b'\n') {
+        // sensors may end their lines either way, whatever --line-ending says
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        if !valid_line(line) {
+            batch.skipped_rows += 1;
+            continue;
+        }
+        let (name, measurement) = parse_line(line);
+        if name.len() > options.max_name_len
+            || (reject_invalid_utf8 && std::str::from_utf8(name).is_err())
+        {
+            batch.skipped_rows += 1;
+            continue;
+        }
+        batch.stations.add_measurement(name, measurement);
+        batch.rows += 1;
+    }
+}
+/// Whether the line is `<name>;<measurement>` with a measurement from -99.9 to 99.9 with one fractional digit,
+/// the only format `parse_line` handles
+fn valid_line(line: &[u8]) -> bool {
+    let Some(semicolon) = line.iter().rposition(|b| *b == b';') else {
+        return false;
+    };
+    let measurement = &line[semicolon + 1..];
+    let digits = measurement.strip_prefix(b"-").unwrap_or(measurement);
+    match digits {
+        [whole @ .., b'.', fraction] if (1..=2).contains(&whole.len()) => {
+            whole.iter().chain([fraction]).all(u8::is_ascii_digit)
+        }
+        _ => false,
+    }
+}
+
+/// Reads lines from one TCP connection until it is closed
+fn receive_tcp(mut stream: TcpStream, shards: &Shards, config: &Config) -> io::Result<()> {
+    let options = config.parse_options(crate::LineEnding::Lf);
+    let reject_invalid_utf8 = config.invalid_utf8 == InvalidUtf8Policy::Reject;
+    let mut buf = vec![0; MIN_BUF_LEN.max(config.max_name_len + 8)];
+    let mut filled = 0;
+    // set when the buffer filled up without a newline, the rest of the line is dropped until the next newline
+    let mut skipping_line = false;
+    loop {
+        let n = stream.read(&mut buf[filled..])?;
+        let mut batch = Aggregated::default();
+        if n == 0 {
+            // the last line of a closed connection is complete, like the last line of a file
+            if skipping_line {
+                batch.skipped_rows += 1;
+            } else {
+                add_lines(&mut batch, &buf[..filled], options, reject_invalid_utf8);
+            }
+            shards.add(batch);
+            return Ok(());
+        }
+        filled += n;
+        let Some(newline) = buf[..filled].iter().rposition(|b| *b == b'\n') else {
+            if filled == buf.len() {
+                // longer than any valid line
+                skipping_line = true;
+                filled = 0;
+            }
+            continue;
+        };
+        let mut start = 0;
+        if skipping_line {
+            start = buf[..filled].iter().position(|b| *b == b'\n').unwrap() + 1;
+            batch.skipped_rows += 1;
+            skipping_line = false;
+        }
+        add_lines(
+            &mut batch,
+            &buf[start..newline.max(start)],
+            options,
+            reject_invalid_utf8,
+        );
+        shards.add(batch);
+        buf.copy_within(newline + 1..filled, 0);
+        filled -= newline + 1;
+    }
+}
+fn accept_tcp(
+    listener: TcpListener,
+    address: SocketAddr,
+    shards: Arc<Shards>,
+    config: Arc<Config>,
+) -> ! {
+    let mut backoff = Backoff::new();
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("tcp {address}: {e}");
+                backoff.failed();
+                continue;
+            }
+        };
+        backoff.reset();
+        let shards = shards.clone();
+        let config = config.clone();
+        thread::spawn(move || {
+            if let Err(e) = receive_tcp(stream, &shards, &config) {
+                eprintln!("tcp {peer}: {e}");
+            }
+        });
+    }
+}
+fn receive_udp(
+    socket: UdpSocket,
+    address: SocketAddr,
+    shards: Arc<Shards>,
+    config: Arc<Config>,
+) -> ! {
+    let options = config.parse_options(crate::LineEnding::Lf);
+    let reject_invalid_utf8 = config.invalid_utf8 == InvalidUtf8Policy::Reject;
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    let mut backoff = Backoff::new();
+    loop {
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("udp {address}: {e}");
+                backoff.failed();
+                continue;
+            }
+        };
+        backoff.reset();
+        let mut batch = Aggregated::default();
+        add_lines(&mut batch, &buf[..n], options, reject_invalid_utf8);
+        shards.add(batch);
+    }
+}
+
+/// The sockets of a running daemon
+pub struct Ingest {
+    pub tcp: Option<SocketAddr>,
+    pub udp: Option<SocketAddr>,
+    pub shards: Arc<Shards>,
+}
+/// Binds `--tcp` and `--udp` and starts receiving on them in the background
+pub fn start(config: &Config) -> Result<Ingest, Error> {
+    let listen_error = |address: SocketAddr| {
+        move |error| Error::Listen {
+            address: address.to_string(),
+            error,
+        }
+    };
+    let shards = Arc::new(Shards::new(config.threads()));
+    let shared_config = Arc::new(config.clone());
+    let mut ingest = Ingest {
+        tcp: None,
+        udp: None,
+        shards: shards.clone(),
+    };
+    if let Some(address) = config.tcp {
+        let listener = TcpListener::bind(address).map_err(listen_error(address))?;
+        let address = listener.local_addr().map_err(listen_error(address))?;
+        ingest.tcp = Some(address);
+        let (shards, config) = (shards.clone(), shared_config.clone());
+        thread::spawn(move || accept_tcp(listener, address, shards, config));
+    }
+    if let Some(address) = config.udp {
+        let socket = UdpSocket::bind(address).map_err(listen_error(address))?;
+        let address = socket.local_addr().map_err(listen_error(address))?;
+        ingest.udp = Some(address);
+        thread::spawn(move || receive_udp(socket, address, shards, shared_config));
+    }
+    Ok(ingest)
+}
+/// Receives lines until the process is stopped, serving the results over HTTP
+pub fn ingest(config: &Config) -> Result<Vec<u8>, Error> {
+    if config.tcp.is_none() && config.udp.is_none() {
+        usage("ingest needs --tcp, --udp or both");
+    }
+    let listener = serve::listen(config)?;
+    let ingest = start(config)?;
+    for (protocol, address) in [("tcp", ingest.tcp), ("udp", ingest.udp)] {
+        if let Some(address) = address {
+            eprintln!("receiving lines on {protocol} {address}");
+        }
+    }
+    let config = config.clone();
+    serve::accept(listener, move || Arc::new(ingest.shards.snapshot(&config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpStream, UdpSocket},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{start, valid_line};
+    use crate::Config;
+
+    #[test]
+    fn validates_lines_for_parse_line() {
+        for line in ["a;1.0", "a;-1.0", "a;99.9", "a;-99.9", ";0.0", "a;b;12.3"] {
+            assert!(valid_line(line.as_bytes()), "{line}");
+        }
+        for line in [
+            "a", "a;", "a;1", "a;1.", "a;.5", "a;100.0", "a;1.00", "a;+1.0", "a;1,0", "a;--1.0",
+        ] {
+            assert!(!valid_line(line.as_bytes()), "{line}");
+        }
+    }
+
+    #[test]
+    fn ingests_tcp_and_udp() {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let config = Config {
+            tcp: Some(localhost),
+            udp: Some(localhost),
+            threads: Some(4.try_into().unwrap()),
+            ..Default::default()
+        };
+        let ingest = start(&config).unwrap();
+
+        let mut sensor = TcpStream::connect(ingest.tcp.unwrap()).unwrap();
+        // a line split over two writes, a malformed line and a line longer than the read buffer
+        sensor.write_all(b"Oslo;-3.0\nHelsinki;1").unwrap();
+        sensor.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        sensor.write_all(b".5\r\nnot a line\n").unwrap();
+        sensor.write_all(&[b'x'; 100_000]).unwrap();
+        sensor.write_all(b";1.0\nOslo;7.0").unwrap();
+        drop(sensor);
+        let udp = UdpSocket::bind(localhost).unwrap();
+        udp.send_to(b"Helsinki;3.5\nOslo;-5.0", ingest.udp.unwrap())
+            .unwrap();
+
+        let start = Instant::now();
+        let snapshot = loop {
+            let snapshot = ingest.shards.snapshot(&config);
+            if snapshot.rows + snapshot.skipped == 7 || start.elapsed() > Duration::from_secs(5) {
+                break snapshot;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(snapshot.rows, 5);
+        assert_eq!(snapshot.skipped, 2);
+        assert_eq!(
+            crate::output::render(crate::OutputFormat::Text, &snapshot.stations),
+            b"{Helsinki=1.5/2.5/3.5, Oslo=-5.0/-0.3/7.0}\n"
+        );
+    }
+}
@@ -1,20 +1,535 @@
+#[cfg(feature = "arrow")]
+mod arrow;
+mod buffer;
+mod checkpoint;
+mod diff;
+mod follow;
+mod incremental;
+mod ingest;
+mod merge;
+mod names;
+mod openmetrics;
+mod output;
+mod progress;
+mod reader;
+mod scan;
+mod serialize;
+mod serve;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod swar;
+mod table;
+mod text;
+mod tune;
+
 use std::{
     collections::HashMap,
+    fmt::Display,
     fs::File,
     hash::{BuildHasherDefault, Hasher},
-    io::{BufRead, BufReader, Read, Seek},
+    io::{BufRead, BufReader, Read, Seek, Write},
+    net::SocketAddr,
     num::NonZeroUsize,
     ops::{Add, BitXor},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+use buffer::{AlignedBuf, MAX_BUF_LEN, MIN_BUF_LEN};
+use checkpoint::{Checkpointer, Part};
+use names::InvalidUtf8Policy;
+use output::OutputFormat;
+use progress::Progress;
+use reader::{ChunkReader, ReaderKind};
+use scan::{Bytewise, ScanKind, Scanner, Swar};
+use stats::{RunStats, StatsFormat, WorkerStats};
+use swar::{parse_line_swar, parse_measurement_swar, ParserKind};
+use table::{OpenTable, StationTable, TableKind};
+
 fn main() {
-    let file_name = std::env::args().nth(1);
-    let res = calc(file_name);
-    println!("{res}");
-    return;
+    let mut args = std::env::args().skip(1).peekable();
+    // diff exits like diff(1), with 1 when there are differences and 2 on errors
+    let (mut success_code, mut error_code) = (0, 1);
+    let res = match args
+        .next_if(|a| ["tune", "merge", "diff", "follow", "serve", "ingest"].contains(&a.as_str()))
+        .as_deref()
+    {
+        Some("tune") => tune::tune(&Config::from_args(args)).map(|res| (res + "\n").into_bytes()),
+        Some("merge") => merge::merge(args),
+        Some("diff") => {
+            error_code = 2;
+            diff::diff(args).inspect(|report| success_code = (!report.is_empty()).into())
+        }
+        Some("follow") => follow::follow(&Config::from_args(args)),
+        Some("serve") => serve::serve(&Config::from_args(args)),
+        Some("ingest") => ingest::ingest(&Config::from_args(args)),
+        _ => calc(&Config::from_args(args)),
+    };
+    match res {
+        Ok(res) => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&res).unwrap();
+            stdout.flush().unwrap();
+            std::process::exit(success_code);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(error_code);
+        }
+    }
+}
+#[derive(Clone)]
+struct Config {
+    file_name: String,
+    /// `None` means the line ending is detected from the start of the input
+    line_ending: Option<LineEnding>,
+    invalid_utf8: InvalidUtf8Policy,
+    /// Normalize station names to Unicode NFC, merging names that only differ in their normalization form
+    nfc: bool,
+    max_name_len: usize,
+    long_lines: LongLinePolicy,
+    /// Amount of worker threads, defaults to available parallelism
+    threads: Option<NonZeroUsize>,
+    table: TableKind,
+    scanner: ScanKind,
+    parser: ParserKind,
+    /// Write statistics of the run to stderr
+    stats: Option<StatsFormat>,
+    /// Show the progress on stderr, if it is a terminal
+    progress: bool,
+    reader: ReaderKind,
+    /// Length of the read buffer of each worker, `None` means it is picked from the file size and thread count
+    buf_len: Option<usize>,
+    /// State file to save the progress of the run to
+    checkpoint: Option<String>,
+    checkpoint_interval: Duration,
+    /// Continue from the state file instead of starting over
+    resume: bool,
+    /// Index file to aggregate only what was appended to the input since the previous run
+    state: Option<String>,
+    format: OutputFormat,
+    /// How often `follow` writes the results if there are new rows, zero means only on request
+    emit_interval: Duration,
+    /// Address `serve` listens on
+    listen: SocketAddr,
+    /// `serve` keeps following the file instead of aggregating it once
+    follow: bool,
+    /// SQLite database to add the run and its results to
+    sqlite: Option<String>,
+    /// Names of the station and value columns of Parquet and Arrow input
+    columns: (String, String),
+    /// Addresses `ingest` receives lines on
+    tcp: Option<SocketAddr>,
+    udp: Option<SocketAddr>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            file_name: "measurements.txt".into(),
+            line_ending: None,
+            invalid_utf8: InvalidUtf8Policy::default(),
+            nfc: false,
+            // the challenge rules cap station names to 100 bytes
+            max_name_len: 100,
+            long_lines: LongLinePolicy::Error,
+            threads: None,
+            table: TableKind::default(),
+            scanner: ScanKind::default(),
+            parser: ParserKind::default(),
+            stats: None,
+            progress: false,
+            reader: ReaderKind::default(),
+            buf_len: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(10),
+            resume: false,
+            state: None,
+            format: OutputFormat::default(),
+            emit_interval: Duration::from_secs(10),
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            follow: false,
+            sqlite: None,
+            columns: ("station".into(), "value".into()),
+            tcp: None,
+            udp: None,
+        }
+    }
+}
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Config {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--line-ending" => {
+                    config.line_ending = match args.next().as_deref() {
+                        Some("lf") => Some(LineEnding::Lf),
+                        Some("crlf") => Some(LineEnding::CrLf),
+                        Some("auto") => None,
+                        _ => usage("--line-ending expects one of: lf, crlf, auto"),
+                    }
+                }
+                "--invalid-utf8" => {
+                    config.invalid_utf8 = args
+                        .next()
+                        .as_deref()
+                        .and_then(InvalidUtf8Policy::parse)
+                        .unwrap_or_else(|| {
+                            usage("--invalid-utf8 expects one of: reject, escape, lossy")
+                        })
+                }
+                "--nfc" => config.nfc = true,
+                "--progress" => config.progress = true,
+                "--max-name-len" => {
+                    config.max_name_len = match args.next().map(|n| n.parse::<usize>()) {
+                        // a line has to fit into the read buffer with the measurement and line ending
+                        Some(Ok(n)) if n > 0 && n <= MAX_BUF_LEN - 8 => n,
+                        _ => usage(&format!(
+                            "--max-name-len expects a number between 1 and {}",
+                            MAX_BUF_LEN - 8
+                        )),
+                    }
+                }
+                "--checkpoint" => {
+                    config.checkpoint = Some(
+                        args.next()
+                            .unwrap_or_else(|| usage("--checkpoint expects a file name")),
+                    )
+                }
+                "--checkpoint-interval" => {
+                    config.checkpoint_interval = match args.next().map(|n| n.parse::<u64>()) {
+                        Some(Ok(n)) => Duration::from_secs(n),
+                        _ => usage("--checkpoint-interval expects a number of seconds"),
+                    }
+                }
+                "--resume" => config.resume = true,
+                "--state" => {
+                    config.state = Some(
+                        args.next()
+                            .unwrap_or_else(|| usage("--state expects a file name")),
+                    )
+                }
+                "--emit-interval" => {
+                    config.emit_interval = match args.next().map(|n| n.parse::<u64>()) {
+                        Some(Ok(n)) => Duration::from_secs(n),
+                        _ => usage("--emit-interval expects a number of seconds"),
+                    }
+                }
+                "--listen" => {
+                    config.listen = match args.next().map(|a| a.parse()) {
+                        Some(Ok(addr)) => addr,
+                        _ => usage("--listen expects an address such as 127.0.0.1:8080"),
+                    }
+                }
+                "--follow" => config.follow = true,
+                "--sqlite" => {
+                    if !cfg!(feature = "sqlite") {
+                        usage("--sqlite needs a build with the sqlite feature");
+                    }
+                    config.sqlite = Some(
+                        args.next()
+                            .unwrap_or_else(|| usage("--sqlite expects a file name")),
+                    )
+                }
+                "--columns" => {
+                    config.columns = match args.next().as_deref().map(|c| c.split_once(',')) {
+                        Some(Some((station, value))) => (station.into(), value.into()),
+                        _ => usage("--columns expects the station and value columns as <station>,<value>"),
+                    }
+                }
+                "--tcp" => {
+                    config.tcp = match args.next().map(|a| a.parse()) {
+                        Some(Ok(addr)) => Some(addr),
+                        _ => usage("--tcp expects an address such as 127.0.0.1:9000"),
+                    }
+                }
+                "--udp" => {
+                    config.udp = match args.next().map(|a| a.parse()) {
+                        Some(Ok(addr)) => Some(addr),
+                        _ => usage("--udp expects an address such as 127.0.0.1:9000"),
+                    }
+                }
+                "--format" => config.format = OutputFormat::from_arg(args.next()),
+                "--buffer-size" => {
+                    config.buf_len = match args.next().as_deref().map(|n| (n, n.parse::<usize>())) {
+                        Some(("auto", _)) => None,
+                        Some((_, Ok(n))) if (MIN_BUF_LEN..=MAX_BUF_LEN).contains(&n) => Some(n),
+                        _ => usage(&format!(
+                            "--buffer-size expects auto or a number between {MIN_BUF_LEN} and {MAX_BUF_LEN}"
+                        )),
+                    }
+                }
+                "--threads" => {
+                    config.threads = match args.next().map(|n| n.parse::<NonZeroUsize>()) {
+                        Some(Ok(n)) => Some(n),
+                        _ => usage("--threads expects a positive number"),
+                    }
+                }
+                "--table" => {
+                    config.table = args
+                        .next()
+                        .as_deref()
+                        .and_then(TableKind::parse)
+                        .unwrap_or_else(|| usage("--table expects one of: std, open"))
+                }
+                "--scanner" => {
+                    config.scanner = args
+                        .next()
+                        .as_deref()
+                        .and_then(ScanKind::parse)
+                        .unwrap_or_else(|| usage("--scanner expects one of: bytewise, swar, simd"))
+                }
+                "--parser" => {
+                    config.parser = args
+                        .next()
+                        .as_deref()
+                        .and_then(ParserKind::parse)
+                        .unwrap_or_else(|| usage("--parser expects one of: match, swar"))
+                }
+                "--reader" => {
+                    config.reader = args
+                        .next()
+                        .as_deref()
+                        .and_then(ReaderKind::parse)
+                        .unwrap_or_else(|| usage("--reader expects one of: blocking, uring, mmap"))
+                }
+                "--stats" => {
+                    config.stats = Some(
+                        args.next()
+                            .as_deref()
+                            .and_then(StatsFormat::parse)
+                            .unwrap_or_else(|| usage("--stats expects one of: text, json")),
+                    )
+                }
+                "--long-lines" => {
+                    config.long_lines = match args.next().as_deref() {
+                        Some("error") => LongLinePolicy::Error,
+                        Some("skip") => LongLinePolicy::Skip,
+                        _ => usage("--long-lines expects one of: error, skip"),
+                    }
+                }
+                flag if flag.starts_with("--") => usage(&format!("unknown option {flag}")),
+                _ => config.file_name = arg,
+            }
+        }
+        if config.buf_len.is_some_and(|n| n < config.max_name_len + 8) {
+            usage("--buffer-size must be at least --max-name-len + 8 bytes");
+        }
+        if config.resume && config.checkpoint.is_none() {
+            usage("--resume needs the state file with --checkpoint");
+        }
+        if config.state.is_some() && config.checkpoint.is_some() {
+            usage("--state cannot be combined with --checkpoint");
+        }
+        config
+    }
+    fn parse_options(&self, line_ending: LineEnding) -> ParseOptions {
+        ParseOptions {
+            line_ending: self.line_ending.unwrap_or(line_ending),
+            max_name_len: self.max_name_len,
+            long_lines: self.long_lines,
+            parser: self.parser,
+        }
+    }
+    fn threads(&self) -> usize {
+        self.threads.map(NonZeroUsize::get).unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(1)
+        })
+    }
+}
+fn usage(msg: &str) -> ! {
+    usage_with_code(msg, 1)
+}
+/// `usage` for `diff`, where 1 means that there are differences
+fn usage_with_code(msg: &str, code: i32) -> ! {
+    eprintln!("{msg}");
+    eprintln!("Usage: brc-rs [tune] [options] [file name]");
+    eprintln!("       brc-rs merge [--format text|json|binary|openmetrics|arrow|parquet] <results file>...");
+    eprintln!("       brc-rs diff [--tolerance <degrees>] <results file> <results file>");
+    eprintln!("       brc-rs follow [options] [file name]");
+    eprintln!("       brc-rs serve [--listen <address>] [--follow] [options] [file name]");
+    eprintln!(
+        "       brc-rs ingest [--tcp <address>] [--udp <address>] [--listen <address>] [options]"
+    );
+    eprintln!("    tune: benchmark read buffer sizes on the file and recommend one");
+    eprintln!("    merge: combine results written with --format json or binary");
+    eprintln!("    diff: list the stations added, removed or changed between two results files, exit with 1 if there are any and 2 on errors");
+    eprintln!("    follow: keep aggregating lines appended to the file, write the results every --emit-interval or when a line is entered on stdin");
+    eprintln!("    serve: answer HTTP queries for the stations on --listen (127.0.0.1:8080 by default), aggregating the file once or following it with --follow");
+    eprintln!("    ingest: aggregate station;value lines received on --tcp and --udp, answering HTTP queries like serve");
+    eprintln!("Options:");
+    eprintln!("    --line-ending lf|crlf|auto");
+    eprintln!("    --invalid-utf8 reject|escape|lossy");
+    eprintln!("    --nfc");
+    eprintln!("    --max-name-len <bytes>");
+    eprintln!("    --long-lines error|skip");
+    eprintln!("    --threads <count>");
+    eprintln!("    --table std|open");
+    eprintln!("    --scanner bytewise|swar|simd");
+    eprintln!("    --parser match|swar");
+    eprintln!("    --reader blocking|uring|mmap");
+    eprintln!("    --stats text|json");
+    eprintln!("    --progress");
+    eprintln!("    --buffer-size auto|<bytes>");
+    eprintln!("    --checkpoint <state file>");
+    eprintln!("    --checkpoint-interval <seconds>");
+    eprintln!("    --resume");
+    eprintln!("    --state <index file>");
+    eprintln!("    --format text|json|binary|openmetrics|arrow|parquet");
+    eprintln!("    --emit-interval <seconds>");
+    eprintln!("    --sqlite <database file>");
+    eprintln!("    --columns <station column>,<value column>");
+    eprintln!("    --listen <address>");
+    eprintln!("    --follow");
+    eprintln!("    --tcp <address>");
+    eprintln!("    --udp <address>");
+    std::process::exit(code);
+}
+#[derive(Debug)]
+enum Error {
+    InvalidStationName {
+        name: String,
+        rows: usize,
+    },
+    /// `line_start` has the beginning of the offending line, as the whole line might be arbitrarily long
+    NameTooLong {
+        line_start: String,
+        max_name_len: usize,
+    },
+    /// The input could not be opened or read
+    Read {
+        path: String,
+        error: std::io::Error,
+    },
+    Checkpoint {
+        path: String,
+        error: std::io::Error,
+    },
+    /// The index of `--state` could not be written
+    State {
+        path: String,
+        error: std::io::Error,
+    },
+    /// `serve` could not listen on the address
+    Listen {
+        address: String,
+        error: std::io::Error,
+    },
+    /// A Parquet or Arrow input file could not be read
+    #[cfg(feature = "arrow")]
+    Input {
+        path: String,
+        error: String,
+    },
+    /// The run could not be added to the `--sqlite` database
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        path: String,
+        error: String,
+    },
+    /// A results file given to `merge` or `diff` could not be read
+    Results {
+        path: String,
+        error: std::io::Error,
+    },
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidStationName { name, rows } => write!(
+                f,
+                "station name \"{name}\" is not valid UTF-8 ({rows} rows), see --invalid-utf8"
+            ),
+            Error::NameTooLong {
+                line_start,
+                max_name_len,
+            } => write!(
+                f,
+                "line starting with \"{line_start}\" has a station name longer than {max_name_len} bytes, see --max-name-len and --long-lines"
+            ),
+            Error::Read { path, error } => write!(f, "{path}: {error}"),
+            Error::Checkpoint { path, error } => write!(f, "checkpoint {path}: {error}"),
+            Error::State { path, error } => write!(f, "state {path}: {error}"),
+            Error::Listen { address, error } => write!(f, "listen {address}: {error}"),
+            #[cfg(feature = "arrow")]
+            Error::Input { path, error } => write!(f, "{path}: {error}"),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite { path, error } => write!(f, "sqlite {path}: {error}"),
+            Error::Results { path, error } => write!(f, "results {path}: {error}"),
+        }
+    }
+}
+/// What to do with rows where the station name is longer than the configured maximum
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LongLinePolicy {
+    Error,
+    /// Skip the row, reporting the count of skipped rows at the end
+    Skip,
+}
+impl LongLinePolicy {
+    #[cfg(feature = "sqlite")]
+    fn as_str(self) -> &'static str {
+        match self {
+            LongLinePolicy::Error => "error",
+            LongLinePolicy::Skip => "skip",
+        }
+    }
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineEnding {
+    Lf,
+    CrLf,
+}
+impl LineEnding {
+    /// Looks at the first complete line of `sample`, defaulting to `Lf` if there is none
+    fn detect(sample: &[u8]) -> LineEnding {
+        match sample.iter().position(|b| *b == b'\n') {
+            Some(idx) if idx > 0 && sample[idx - 1] == b'\r' => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "lf",
+            LineEnding::CrLf => "crlf",
+        }
+    }
+    /// Removes the `\r` in front of the already stripped `\n`.
+    /// The last line of a file might be missing the line ending entirely, so the `\r` is checked instead of blindly cut.
+    #[inline]
+    fn trim(self, line: &[u8]) -> &[u8] {
+        match (self, line.last()) {
+            (LineEnding::CrLf, Some(b'\r')) => &line[..line.len() - 1],
+            _ => line,
+        }
+    }
+}
+/// Everything `aggregate_measurements` needs to know about the lines, shared by all chunks
+#[derive(Clone, Copy)]
+struct ParseOptions {
+    line_ending: LineEnding,
+    max_name_len: usize,
+    long_lines: LongLinePolicy,
+    parser: ParserKind,
+}
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            line_ending: LineEnding::Lf,
+            max_name_len: 100,
+            long_lines: LongLinePolicy::Error,
+            parser: ParserKind::default(),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
 struct WeatherStationStats {
     min: i64,
     max: i64,
@@ -38,17 +553,13 @@ impl Add<&mut Self> for WeatherStationStats {
         }
     }
 }
-fn parse_line(mut line: &[u8]) -> (&[u8], i64) {
+fn parse_line(line: &[u8]) -> (&[u8], i64) {
     // we know that the measurement is pure ASCII and is at max 5 characters long
     // based on this we can find the semicolon faster by doing at most 6 byte comparisons by iterating the reversed bytes
     // At the same time, we _are_ iterating through the measurement from the least significant character to the biggest
     let mut semicolon_idx = 0;
     let mut is_negative = false;
     let mut measurement = 0;
-    // stupid Windows check
-    if cfg!(windows) && line[line.len() - 1] == b'\r' {
-        line = &line[..line.len() - 1];
-    }
     for (idx, b) in line.into_iter().rev().take(6).enumerate() {
         match (b, idx) {
             (b';', _) => {
@@ -81,94 +592,470 @@ fn parse_line(mut line: &[u8]) -> (&[u8], i64) {
         },
     )
 }
+/// Parses the measurement part of a line, for when the scanner already found the separator
+#[inline]
+fn parse_measurement(value: &[u8]) -> i64 {
+    let (is_negative, digits) = match value {
+        [b'-', digits @ ..] => (true, digits),
+        digits => (false, digits),
+    };
+    let measurement = match digits {
+        [a, b'.', b] => (a - b'0') as i64 * 10 + (b - b'0') as i64,
+        [a, b, b'.', c] => (a - b'0') as i64 * 100 + (b - b'0') as i64 * 10 + (c - b'0') as i64,
+        _ => panic!("{:#?}", String::from_utf8_lossy(value)),
+    };
+    if is_negative {
+        -measurement
+    } else {
+        measurement
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
 struct Chunk {
     start_point: u64,
     len: usize,
-    outer_map: Arc<Mutex<HashMap<Vec<u8>, WeatherStationStats>>>,
 }
-fn chunk_le_file<T: BufRead + Seek>(
+fn chunk_le_file<T: BufRead + Seek>(f: T, file_len: usize, chunk_count: usize) -> Vec<Chunk> {
+    chunk_range(f, 0, file_len, chunk_count)
+}
+/// Splits the part of the file from `start`, which has to be at the start of a line, to `file_len`
+fn chunk_range<T: BufRead + Seek>(
     mut f: T,
+    start: u64,
     file_len: usize,
-    arccimuuteksi: Arc<Mutex<HashMap<Vec<u8>, WeatherStationStats>>>,
+    chunk_count: usize,
 ) -> Vec<Chunk> {
-    let chunk_count = std::thread::available_parallelism()
-        .map(NonZeroUsize::get)
-        .unwrap_or(1)
-    // do a sneaky 4x chunks vs available threads to allow OS scheduler to switch between threads,
-    // potentially enabling I/O blocked threads being swapped to threads where I/O is not blocked.
-    // 4 was tested to provide best perf with both M1 Macbook Max and Ryzen 5950x
-    * 4;
-    let chunk_size = file_len / chunk_count + 1;
-    // max length of line is 100 bytes station name, ';', '-99.9', '\n'
-    let mut tmp_arr = Vec::with_capacity(107);
+    let chunk_size = (file_len - start as usize) / chunk_count + 1;
     let mut res = vec![];
-    let mut cur_start = 0;
+    let mut cur_start = start;
+    f.seek(std::io::SeekFrom::Start(start)).unwrap();
     for _ in 0..chunk_count {
+        // small files with a lot of chunks run out of lines before chunks, don't create empty chunks past the end
+        if cur_start >= file_len as u64 {
+            break;
+        }
         f.seek(std::io::SeekFrom::Current(chunk_size as i64))
             .unwrap();
-        f.read_until(b'\n', &mut tmp_arr).unwrap();
-        let end_pos = f.stream_position().unwrap();
+        // a line can be arbitrarily long if the input is broken, so skip instead of reading the rest of the line into memory
+        f.skip_until(b'\n').unwrap();
+        // seeking past the end of the file is allowed, and the last line might not end in a newline,
+        // in both cases the chunk ends at the end of the file
+        let end_pos = f.stream_position().unwrap().min(file_len as u64);
         res.push(Chunk {
             start_point: cur_start,
             len: (end_pos - cur_start) as usize,
-            outer_map: arccimuuteksi.clone(),
         });
-        tmp_arr.clear();
         cur_start = end_pos
     }
     res
 }
-fn calc(file_name: Option<String>) -> String {
-    let file_name: Arc<str> = file_name.unwrap_or("measurements.txt".into()).into();
-    let f = File::open(file_name.to_string()).unwrap();
-    let file_len = f.metadata().unwrap().len() as usize;
-    let stations = Arc::new(Mutex::new(HashMap::<Vec<u8>, WeatherStationStats>::new()));
-    let chunks = chunk_le_file(BufReader::new(f), file_len, stations.clone());
-    let handles = chunks
-        .into_iter()
-        .map(|c| {
-            let file_name = file_name.clone();
-            thread::spawn(move || {
-                let mut f = File::open(file_name.to_string()).unwrap();
-                f.seek(std::io::SeekFrom::Start(c.start_point)).unwrap();
-                let f = f.take(c.len as u64);
-                let stations_välipala = aggregate_measurements(f);
-                let mut stations = c.outer_map.lock().unwrap();
-                for (k, v) in stations_välipala {
-                    match stations.get_mut(&k) {
-                        Some(jutska) => *jutska = v + jutska,
-                        None => {
-                            stations.insert(k, v);
+/// Length of a chunk to aim for. Small enough for the workers to finish at roughly the same time,
+/// big enough for the chunking and the shared queue to not show up in the profile
+const TARGET_CHUNK_LEN: usize = 4 * 1024 * 1024;
+/// Everything the workers share
+struct Job<'a> {
+    file_name: &'a str,
+    chunks: &'a [Chunk],
+    threads: usize,
+    options: ParseOptions,
+    reader: ReaderKind,
+    buf_len: usize,
+    progress: Option<&'a Progress>,
+    checkpoint: Option<&'a Checkpointer>,
+    /// Stations aggregated before this run, from a resumed checkpoint or the `--state` index
+    base: Option<&'a Aggregated<StationList>>,
+}
+/// Runs `threads` workers that pull chunks from `chunks` until there are none left.
+/// Each worker aggregates all of its chunks into a single map, which it returns when done
+fn run_workers<T: StationTable, S: Scanner>(
+    job: &Job,
+    scanner: S,
+) -> Vec<Result<(Aggregated<T>, WorkerStats), Error>> {
+    let next_chunk = &AtomicUsize::new(0);
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        let reporter = job.progress.map(|p| s.spawn(|| p.report_until(&done)));
+        let handles = (0..job.threads.min(job.chunks.len()))
+            .map(|worker| {
+                s.spawn(move || {
+                    let start = Instant::now();
+                    let mut last_checkpoint = start;
+                    let mut done_chunks = vec![];
+                    let read_error = |error| Error::Read {
+                        path: job.file_name.to_string(),
+                        error,
+                    };
+                    let mut reader =
+                        ChunkReader::open(job.file_name, job.reader).map_err(read_error)?;
+                    // allocated once per worker instead of per chunk
+                    let mut buf = AlignedBuf::new(job.buf_len, buffer::page_size());
+                    let mut res = Aggregated::<T>::default();
+                    let mut stats = WorkerStats::default();
+                    loop {
+                        let Some(c) = job.chunks.get(next_chunk.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            stats.rows = res.rows;
+                            stats.map_size = res.stations.len();
+                            stats.parse_time = start.elapsed();
+                            break Ok((res, stats));
+                        };
+                        stats.chunk_lens.push(c.len);
+                        reader
+                            .start_chunk(c.start_point, c.len as u64)
+                            .map_err(read_error)?;
+                        aggregate_measurements(
+                            &mut res,
+                            &mut reader,
+                            job.file_name,
+                            &mut buf,
+                            job.options,
+                            scanner,
+                            job.progress,
+                        )?;
+                        if let Some(checkpoint) = job.checkpoint {
+                            done_chunks.push(c.start_point);
+                            if last_checkpoint.elapsed() >= checkpoint.interval {
+                                let aggregated = Aggregated {
+                                    stations: res.stations.snapshot(),
+                                    skipped_rows: res.skipped_rows,
+                                    rows: res.rows,
+                                };
+                                checkpoint.save(
+                                    worker,
+                                    Part {
+                                        done: done_chunks.clone(),
+                                        aggregated,
+                                    },
+                                )?;
+                                last_checkpoint = Instant::now();
+                            }
                         }
                     }
-                }
+                })
             })
-        })
-        .collect::<Vec<_>>();
-    for h in handles {
-        h.join().unwrap()
+            .collect::<Vec<_>>();
+        // joined before propagating a panic of a worker, the scope would wait for the reporter forever otherwise
+        let res = handles.into_iter().map(|h| h.join()).collect::<Vec<_>>();
+        if let Some(reporter) = reporter {
+            done.store(true, Ordering::Relaxed);
+            reporter.thread().unpark();
+        }
+        res.into_iter()
+            .map(|r| r.unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    })
+}
+/// Runs the workers over all chunks and merges their results
+fn aggregate_chunks<T: StationTable, S: Scanner>(
+    job: &Job,
+    scanner: S,
+    stats: &mut RunStats,
+) -> Result<Aggregated<StationList>, Error> {
+    merge_workers(run_workers::<T, S>(job, scanner), job.base, stats)
+}
+/// Merges the results of the workers, and the stations of earlier runs in `base`
+fn merge_workers<T: StationTable>(
+    workers: Vec<Result<(Aggregated<T>, WorkerStats), Error>>,
+    base: Option<&Aggregated<StationList>>,
+    stats: &mut RunStats,
+) -> Result<Aggregated<StationList>, Error> {
+    let mut skipped_rows = 0;
+    let mut rows = 0;
+    let mut maps = Vec::with_capacity(workers.len() + 1);
+    if let Some(base) = base {
+        skipped_rows += base.skipped_rows;
+        rows += base.rows;
+        let mut map = T::default();
+        for (name, stats) in &base.stations {
+            map.add_stats(name.clone(), *stats);
+        }
+        maps.push(map);
+    }
+    for worker in workers {
+        let (aggregated, worker_stats) = worker?;
+        skipped_rows += aggregated.skipped_rows;
+        rows += aggregated.rows;
+        maps.push(aggregated.stations);
+        stats.workers.push(worker_stats);
     }
-    let lock = stations.lock().unwrap();
-    let mut res = lock.iter().collect::<Vec<_>>();
+    stats.end_phase("parse");
+    let stations = merge_stations(maps).into_stations().collect();
+    stats.end_phase("merge");
+    Ok(Aggregated {
+        stations,
+        skipped_rows,
+        rows,
+    })
+}
+/// Picks the scanner implementation. The scanner is a type parameter instead of a runtime value,
+/// so the line loop is compiled separately for each of them
+fn with_scanner<T: StationTable>(
+    kind: ScanKind,
+    job: &Job,
+    stats: &mut RunStats,
+) -> Result<Aggregated<StationList>, Error> {
+    match kind {
+        ScanKind::Bytewise => aggregate_chunks::<T, _>(job, Bytewise, stats),
+        ScanKind::Swar => aggregate_chunks::<T, _>(job, Swar, stats),
+        #[cfg(target_arch = "x86_64")]
+        ScanKind::Simd => match scan::Avx2::detect() {
+            Some(avx2) => aggregate_chunks::<T, _>(job, avx2, stats),
+            None => aggregate_chunks::<T, _>(job, scan::Sse2, stats),
+        },
+        #[cfg(not(target_arch = "x86_64"))]
+        ScanKind::Simd => aggregate_chunks::<T, _>(job, Swar, stats),
+    }
+}
+fn calc(config: &Config) -> Result<Vec<u8>, Error> {
+    let mut stats = RunStats::start();
+    #[cfg(feature = "sqlite")]
+    let started_at = std::time::SystemTime::now();
+    let res = calc_stations(config, &mut stats)?;
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &config.sqlite {
+        sqlite::export(path, config, started_at, &res).map_err(|e| Error::Sqlite {
+            path: path.clone(),
+            error: e.to_string(),
+        })?;
+        stats.end_phase("sqlite");
+    }
+    let output = output::render(config.format, &res.stations);
+    stats.end_phase("format");
+    if let Some(format) = config.stats {
+        eprint!("{}", stats.render(format));
+    }
+    Ok(output)
+}
+/// Aggregates the whole input into stations sorted by name
+fn calc_stations(
+    config: &Config,
+    stats: &mut RunStats,
+) -> Result<Aggregated<Vec<(String, WeatherStationStats)>>, Error> {
+    #[cfg(feature = "arrow")]
+    if let Some(format) = arrow::InputFormat::detect(&config.file_name) {
+        if config.checkpoint.is_some() || config.state.is_some() {
+            usage("--checkpoint and --state only work with text input");
+        }
+        let aggregated = match config.table {
+            TableKind::Std => arrow::aggregate::<StationMap>(config, format, stats)?,
+            TableKind::Open => arrow::aggregate::<OpenTable>(config, format, stats)?,
+        };
+        return resolve_stations(config, aggregated, stats);
+    }
+    let file_name: Arc<str> = config.file_name.as_str().into();
+    let f = File::open(file_name.to_string()).unwrap();
+    let file_len = f.metadata().unwrap().len() as usize;
+    let mut f = BufReader::new(f);
+    // peeking the BufReader does not move the position, so chunking starts from the beginning still
+    let options = config.parse_options(
+        config
+            .line_ending
+            .unwrap_or_else(|| LineEnding::detect(f.fill_buf().unwrap())),
+    );
+    let threads = config.threads();
+    // Previously the file was split into 4x chunks vs available threads, with one thread per chunk,
+    // leaving cores idle at the end whenever the chunks did not finish at the same time.
+    // Now there are a lot of small chunks that a fixed amount of workers pull from a shared queue
+    let chunk_count = (file_len / TARGET_CHUNK_LEN).max(threads);
+    let index = config
+        .state
+        .as_ref()
+        .and_then(|path| load_index(path, &file_name));
+    // what a checkpoint is saved for, and what a resumed one has to match
+    let input = match &config.checkpoint {
+        Some(_) => Some(
+            // a handle of its own, the fingerprints move the position of the file
+            File::open(&*file_name)
+                .and_then(|f| checkpoint::Input::new(&f, options))
+                .map_err(|error| Error::Read {
+                    path: file_name.to_string(),
+                    error,
+                })?,
+        ),
+        None => None,
+    };
+    let (chunks, pending, resumed) = if config.resume {
+        let path = config.checkpoint.as_deref().unwrap();
+        let checkpoint_error = |error| Error::Checkpoint {
+            path: path.to_string(),
+            error,
+        };
+        let mut state = checkpoint::State::load(path.as_ref()).map_err(checkpoint_error)?;
+        state
+            .check(input.as_ref().unwrap())
+            .map_err(checkpoint_error)?;
+        let pending = state.pending();
+        (
+            std::mem::take(&mut state.chunks),
+            pending,
+            state.into_part(),
+        )
+    } else if let Some(index) = &index {
+        let chunks = chunk_range(f, index.len, file_len, chunk_count);
+        (chunks.clone(), chunks, Part::default())
+    } else {
+        let chunks = chunk_le_file(f, file_len, chunk_count);
+        (chunks.clone(), chunks, Part::default())
+    };
+    let checkpointer = config.checkpoint.as_ref().zip(input).map(|(path, input)| {
+        Checkpointer::new(
+            path,
+            config.checkpoint_interval,
+            input,
+            chunks,
+            resumed,
+            threads,
+        )
+    });
+    let to_read = pending.iter().map(|c| c.len).sum();
+    stats.bytes = to_read;
+    let progress = config
+        .progress
+        .then(|| Progress::for_stderr(to_read))
+        .flatten();
+    let reader = match config.reader.check() {
+        Ok(()) => config.reader,
+        Err(e) => {
+            eprintln!(
+                "--reader {} is not available ({e}), using blocking reads",
+                config.reader.as_str()
+            );
+            ReaderKind::Blocking
+        }
+    };
+    let page_size = buffer::page_size();
+    let buf_len = config
+        .buf_len
+        .unwrap_or_else(|| buffer::auto_len(file_len, threads, page_size))
+        // a longer --max-name-len than the automatic size fits
+        .max(buffer::round_to_pages(config.max_name_len + 8, page_size));
+    let job = Job {
+        file_name: &file_name,
+        chunks: &pending,
+        threads,
+        options,
+        reader,
+        buf_len,
+        progress: progress.as_ref(),
+        checkpoint: checkpointer.as_ref(),
+        base: match (&checkpointer, &index) {
+            (Some(checkpointer), _) => Some(&checkpointer.resumed.aggregated),
+            (None, Some(index)) => Some(&index.aggregated),
+            (None, None) => None,
+        },
+    };
+    stats.end_phase("chunking");
+    let aggregated = match config.table {
+        TableKind::Std => with_scanner::<StationMap>(config.scanner, &job, stats)?,
+        TableKind::Open => with_scanner::<OpenTable>(config.scanner, &job, stats)?,
+    };
+    if let Some(path) = &config.state {
+        let saved =
+            incremental::Index::save(path.as_ref(), &file_name, file_len as u64, &aggregated)
+                .map_err(|error| Error::State {
+                    path: path.clone(),
+                    error,
+                })?;
+        if !saved {
+            eprintln!("{file_name} does not end with a newline, not updating the state in {path}");
+        }
+    }
+    let res = resolve_stations(config, aggregated, stats)?;
+    if let Some(checkpointer) = &checkpointer {
+        checkpointer.remove()?;
+    }
+    Ok(res)
+}
+/// Turns the raw names into strings and sorts the stations by them
+fn resolve_stations(
+    config: &Config,
+    aggregated: Aggregated<StationList>,
+    stats: &mut RunStats,
+) -> Result<Aggregated<Vec<(String, WeatherStationStats)>>, Error> {
+    let Aggregated {
+        stations,
+        skipped_rows,
+        rows,
+    } = aggregated;
+    if skipped_rows > 0 {
+        eprintln!(
+            "skipped {skipped_rows} rows with a station name longer than {} bytes",
+            config.max_name_len
+        );
+    }
+    let resolved = names::resolve(stations, config.invalid_utf8, config.nfc)?;
+    if resolved.invalid_rows > 0 {
+        eprintln!(
+            "{} rows had a station name that is not valid UTF-8 (--invalid-utf8 {})",
+            resolved.invalid_rows,
+            config.invalid_utf8.as_str()
+        );
+    }
+    let mut res = resolved.stations;
+    stats.stations = res.len();
+    stats.end_phase("names");
 
     res.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-    String::from("{")
-        + &res
-            .into_iter()
-            .map(|(station, stats)| {
-                format!(
-                    "{}={:.1}/{:.1}/{:.1}",
-                    String::from_utf8_lossy(station),
-                    stats.min as f64 / 10.0,
-                    stats.mean(),
-                    stats.max as f64 / 10.0
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(", ")
-        + &String::from("}\n")
+    stats.end_phase("sort");
+    Ok(Aggregated {
+        stations: res,
+        skipped_rows,
+        rows,
+    })
+}
+
+/// Loads the `--state` index if it is still valid for the input, a missing index is the first run
+fn load_index(path: &str, file_name: &str) -> Option<incremental::Index> {
+    let index = match incremental::Index::load(path.as_ref()) {
+        Ok(index) => index,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            eprintln!("could not read the state in {path} ({e}), aggregating the whole file");
+            return None;
+        }
+    };
+    match index.matches(file_name) {
+        Ok(true) => Some(index),
+        _ => {
+            eprintln!("{file_name} has changed since the state in {path} was saved, aggregating the whole file");
+            None
+        }
+    }
 }
 
+/// Merges the maps of each chunk pairwise in parallel, halving the amount of maps on each round.
+/// Previously every thread merged its map into a single `Mutex<HashMap>`, which serialized the whole merge phase
+fn merge_stations<T: StationTable>(mut maps: Vec<T>) -> T {
+    while maps.len() > 1 {
+        let mut pairs = maps.into_iter();
+        maps = thread::scope(|s| {
+            let mut handles = Vec::with_capacity(pairs.len() / 2 + 1);
+            while let Some(a) = pairs.next() {
+                let b = pairs.next();
+                handles.push(s.spawn(move || match b {
+                    Some(b) => merge_pair(a, b),
+                    None => a,
+                }));
+            }
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+    }
+    maps.pop().unwrap_or_default()
+}
+fn merge_pair<T: StationTable>(mut a: T, mut b: T) -> T {
+    // iterate the smaller one, fewer lookups and less likely to need to grow the bigger one
+    if a.len() < b.len() {
+        std::mem::swap(&mut a, &mut b);
+    }
+    for (k, v) in b.into_stations() {
+        a.add_stats(k, v);
+    }
+    a
+}
+
+type StationMap = HashMap<Vec<u8>, WeatherStationStats, BuildCustomHasher>;
+/// Stations with their raw names, in no particular order
+type StationList = Vec<(Vec<u8>, WeatherStationStats)>;
 type BuildCustomHasher = BuildHasherDefault<CustomHasher>;
 
 #[derive(Default, Clone)]
@@ -215,61 +1102,151 @@ impl Hasher for CustomHasher {
 }
 // yoink end
 
-const CHUNK_SIZE: usize = 500_000;
-fn aggregate_measurements(
+#[derive(Default)]
+struct Aggregated<T = StationMap> {
+    stations: T,
+    /// Rows skipped because of `LongLinePolicy::Skip`
+    skipped_rows: usize,
+    /// Rows added to `stations`
+    rows: usize,
+}
+/// Adds every line from `kontsa` to `res`, so a worker can use the same map for all of its chunks.
+/// `path` is the input `kontsa` reads from, for the read errors
+fn aggregate_measurements<T: StationTable, S: Scanner>(
+    res: &mut Aggregated<T>,
     mut kontsa: impl Read,
-) -> HashMap<Vec<u8>, WeatherStationStats, BuildCustomHasher> {
-    let mut stations = HashMap::with_hasher(BuildCustomHasher::default());
-    let mut buf = [0; CHUNK_SIZE];
-    let mut bytes_read = kontsa.read(&mut buf).unwrap();
+    path: &str,
+    buf: &mut [u8],
+    options: ParseOptions,
+    scanner: S,
+    progress: Option<&Progress>,
+) -> Result<(), Error> {
+    let read_error = |error| Error::Read {
+        path: path.to_string(),
+        error,
+    };
+    let mut bytes_read = kontsa.read(buf).map_err(read_error)?;
+    if let Some(p) = progress {
+        p.add(bytes_read);
+    }
     let mut consumed = 0;
+    // set when the buffer filled up without a newline, the rest of the line is dropped until the next newline
+    let mut skipping_line = false;
     loop {
-        let Some(line_end_idx) = buf[consumed..bytes_read].iter().position(|b| *b == b'\n') else {
+        let Some(delimiters) = scanner.find(&buf[consumed..bytes_read]) else {
+            if consumed == 0 && bytes_read == buf.len() {
+                // The whole buffer is a single line, which would never fit into the buffer.
+                // As the buffer is always longer than max_name_len, this line is too long either way
+                match options.long_lines {
+                    LongLinePolicy::Error => return Err(name_too_long(buf, options)),
+                    LongLinePolicy::Skip => {
+                        skipping_line = true;
+                        consumed = bytes_read;
+                    }
+                }
+            }
             buf.copy_within(consumed..bytes_read, 0);
             let remainder = bytes_read - consumed;
-            bytes_read = kontsa.read(&mut buf[remainder..]).unwrap();
-            // here if we get bytes_read == 0, which means we did not add anything to remaining characters
-            // and as we are here already, we know that there is no valid line
+            bytes_read = kontsa.read(&mut buf[remainder..]).map_err(read_error)?;
+            if let Some(p) = progress {
+                p.add(bytes_read);
+            }
+            // here if we get bytes_read == 0, which means we did not add anything to remaining characters.
+            // Whatever is remaining is the last line of the input, which did not end with a newline
             if bytes_read == 0 {
+                if skipping_line {
+                    res.skipped_rows += 1;
+                } else if remainder > 0 {
+                    add_line(res, &buf[..remainder], None, options)?;
+                }
                 break;
             }
             bytes_read += remainder;
             consumed = 0;
             continue;
         };
-        let (station_name, measurement) = parse_line(&buf[consumed..consumed + line_end_idx]);
-
-        match stations.get_mut(station_name) {
-            None => {
-                stations.insert(
-                    station_name.to_vec(),
-                    WeatherStationStats {
-                        min: measurement,
-                        max: measurement,
-                        sum: measurement,
-                        count: 1,
-                    },
-                );
-            }
-            Some(s) => {
-                s.max = s.max.max(measurement);
-                s.min = s.min.min(measurement);
-                s.count += 1;
-                s.sum += measurement;
-            }
-        };
+        let line = &buf[consumed..consumed + delimiters.newline];
         // We have "consumed" one line of input
-        consumed += line_end_idx + 1;
+        consumed += delimiters.newline + 1;
+        if skipping_line {
+            skipping_line = false;
+            res.skipped_rows += 1;
+            continue;
+        }
+        add_line(res, line, delimiters.semicolon, options)?;
+    }
+    Ok(())
+}
+#[inline]
+fn add_line<T: StationTable>(
+    res: &mut Aggregated<T>,
+    line: &[u8],
+    semicolon: Option<usize>,
+    options: ParseOptions,
+) -> Result<(), Error> {
+    // the parser is a runtime value unlike the table and the scanner, as the branch is always the same for every line
+    let (station_name, measurement) = match (semicolon, options.parser) {
+        (Some(idx), ParserKind::Match) => (
+            &line[..idx],
+            parse_measurement(options.line_ending.trim(&line[idx + 1..])),
+        ),
+        (Some(idx), ParserKind::Swar) => (
+            &line[..idx],
+            parse_measurement_swar(options.line_ending.trim(&line[idx + 1..])),
+        ),
+        (None, ParserKind::Match) => parse_line(options.line_ending.trim(line)),
+        (None, ParserKind::Swar) => parse_line_swar(options.line_ending.trim(line)),
+    };
+    if station_name.len() > options.max_name_len {
+        match options.long_lines {
+            LongLinePolicy::Error => return Err(name_too_long(line, options)),
+            LongLinePolicy::Skip => {
+                res.skipped_rows += 1;
+                return Ok(());
+            }
+        }
+    }
+    res.stations.add_measurement(station_name, measurement);
+    res.rows += 1;
+    Ok(())
+}
+#[cold]
+fn name_too_long(line: &[u8], options: ParseOptions) -> Error {
+    Error::NameTooLong {
+        line_start: String::from_utf8_lossy(&line[..line.len().min(32)]).into_owned(),
+        max_name_len: options.max_name_len,
     }
-    stations
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::read_to_string;
 
+    use std::fs::File;
+    use std::io::{BufReader, Cursor, Read};
+
+    use crate::aggregate_measurements;
     use crate::calc;
+    use crate::chunk_le_file;
+    use crate::diff;
+    use crate::incremental;
+    use crate::merge_stations;
     use crate::parse_line;
+    use crate::run_workers;
+    use crate::Aggregated;
+    use crate::Config;
+    use crate::Error;
+    use crate::Job;
+    use crate::LineEnding;
+    use crate::LongLinePolicy;
+    use crate::ParseOptions;
+    use crate::StationMap;
+    use crate::TableKind;
+    use crate::WeatherStationStats;
+    use crate::MIN_BUF_LEN;
+    use crate::{checkpoint::Input, Checkpointer, Chunk, Part, Progress};
+    use crate::{parse_measurement, Bytewise, ParserKind, ReaderKind, ScanKind, Swar};
+    use std::time::Duration;
     macro_rules! tst_parse_line {
         ($func:ident,$line:expr,$expected:expr) => {
             #[test]
@@ -305,17 +1282,554 @@ mod tests {
         ("StationName", 999)
     );
 
+    fn aggregate(input: &[u8], options: ParseOptions) -> Result<Aggregated, Error> {
+        let mut res = Aggregated::default();
+        let mut buf = vec![0; MIN_BUF_LEN];
+        aggregate_measurements(&mut res, input, "input", &mut buf, options, Bytewise, None)?;
+        Ok(res)
+    }
+    #[test]
+    fn read_errors_are_returned() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+        // fails in the middle of the input, after the first line
+        let input = (&b"a;1.0\nb;2"[..]).chain(Failing);
+        let mut res = Aggregated::<StationMap>::default();
+        let mut buf = vec![0; MIN_BUF_LEN];
+        let err = aggregate_measurements(
+            &mut res,
+            input,
+            "input",
+            &mut buf,
+            ParseOptions::default(),
+            Bytewise,
+            None,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&err, Error::Read { path, error } if path == "input" && error.kind() == std::io::ErrorKind::BrokenPipe),
+            "{err}"
+        );
+    }
+    #[test]
+    fn parse_measurement_matches_parse_line() {
+        for m in -999..=999 {
+            let line = format!("StationName;{:.1}", m as f64 / 10.0);
+            let (_, expected) = parse_line(line.as_bytes());
+            assert_eq!(expected, m);
+            assert_eq!(
+                parse_measurement(&line.as_bytes()[12..]),
+                expected,
+                "{line}"
+            );
+        }
+    }
+    #[test]
+    fn line_ending_detects_lf() {
+        assert_eq!(LineEnding::detect(b"a;1.0\nb;2.0\r\n"), LineEnding::Lf);
+    }
+    #[test]
+    fn line_ending_detects_crlf() {
+        assert_eq!(LineEnding::detect(b"a;1.0\r\nb;2.0\n"), LineEnding::CrLf);
+    }
+    #[test]
+    fn line_ending_defaults_to_lf_without_newline() {
+        assert_eq!(LineEnding::detect(b"a;1.0\r"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(b""), LineEnding::Lf);
+    }
+
+    macro_rules! tst_aggregate {
+        ($func:ident,$input:expr,$line_ending:expr,$expected:expr) => {
+            #[test]
+            fn $func() {
+                let options = ParseOptions {
+                    line_ending: $line_ending,
+                    ..Default::default()
+                };
+                let mut res = aggregate(&$input[..], options)
+                    .unwrap()
+                    .stations
+                    .into_iter()
+                    .map(|(k, v)| (String::from_utf8(k).unwrap(), v.min, v.max, v.sum, v.count))
+                    .collect::<Vec<_>>();
+                res.sort_unstable();
+                let expected: &[(&str, i64, i64, i64, usize)] = &$expected;
+                assert_eq!(
+                    res,
+                    expected
+                        .iter()
+                        .map(|(k, min, max, sum, count)| (k.to_string(), *min, *max, *sum, *count))
+                        .collect::<Vec<_>>()
+                );
+            }
+        };
+    }
+    tst_aggregate!(
+        aggregate_lf,
+        b"a;1.0\nb;-2.5\na;3.0\n",
+        LineEnding::Lf,
+        [("a", 10, 30, 40, 2), ("b", -25, -25, -25, 1)]
+    );
+    tst_aggregate!(
+        aggregate_crlf,
+        b"a;1.0\r\nb;-2.5\r\na;3.0\r\n",
+        LineEnding::CrLf,
+        [("a", 10, 30, 40, 2), ("b", -25, -25, -25, 1)]
+    );
+    tst_aggregate!(
+        aggregate_lf_missing_final_newline,
+        b"a;1.0\nb;-2.5\na;3.0",
+        LineEnding::Lf,
+        [("a", 10, 30, 40, 2), ("b", -25, -25, -25, 1)]
+    );
+    tst_aggregate!(
+        aggregate_crlf_missing_final_newline,
+        b"a;1.0\r\nb;-2.5\r\na;3.0",
+        LineEnding::CrLf,
+        [("a", 10, 30, 40, 2), ("b", -25, -25, -25, 1)]
+    );
+    tst_aggregate!(aggregate_empty_input, b"", LineEnding::Lf, []);
+
+    macro_rules! tst_chunked {
+        ($func:ident,$input:expr,$chunk_count:expr,$expected:expr) => {
+            #[test]
+            fn $func() {
+                let input: &[u8] = $input;
+                let chunks = chunk_le_file(Cursor::new(input), input.len(), $chunk_count);
+                // chunks must cover the whole input without gaps or overlap, even without the trailing newline
+                assert_eq!(chunks.first().map(|c| c.start_point), Some(0));
+                for w in chunks.windows(2) {
+                    assert_eq!(w[0].start_point + w[0].len as u64, w[1].start_point);
+                }
+                let last = chunks.last().unwrap();
+                assert_eq!(last.start_point as usize + last.len, input.len());
+                let maps = chunks
+                    .iter()
+                    .map(|c| {
+                        let start = c.start_point as usize;
+                        aggregate(&input[start..start + c.len], ParseOptions::default())
+                            .unwrap()
+                            .stations
+                    })
+                    .collect();
+                let mut res = merge_stations(maps)
+                    .into_iter()
+                    .map(|(k, v)| (String::from_utf8(k).unwrap(), v.min, v.max, v.sum, v.count))
+                    .collect::<Vec<_>>();
+                res.sort_unstable();
+                let expected: &[(&str, i64, i64, i64, usize)] = &$expected;
+                assert_eq!(
+                    res,
+                    expected
+                        .iter()
+                        .map(|(k, min, max, sum, count)| (k.to_string(), *min, *max, *sum, *count))
+                        .collect::<Vec<_>>()
+                );
+            }
+        };
+    }
+    tst_chunked!(
+        chunked_last_chunk_without_newline,
+        b"a;1.0\nb;-2.5\na;3.0\nc;4.4",
+        2,
+        [
+            ("a", 10, 30, 40, 2),
+            ("b", -25, -25, -25, 1),
+            ("c", 44, 44, 44, 1)
+        ]
+    );
+    tst_chunked!(
+        chunked_more_chunks_than_lines_without_newline,
+        b"a;1.0\nb;-2.5\na;3.0\nc;4.4",
+        16,
+        [
+            ("a", 10, 30, 40, 2),
+            ("b", -25, -25, -25, 1),
+            ("c", 44, 44, 44, 1)
+        ]
+    );
+    tst_chunked!(
+        chunked_single_line_without_newline,
+        b"c;-4.4",
+        4,
+        [("c", -44, -44, -44, 1)]
+    );
+    tst_chunked!(
+        chunked_with_trailing_newline,
+        b"a;1.0\nb;-2.5\na;3.0\nc;4.4\n",
+        3,
+        [
+            ("a", 10, 30, 40, 2),
+            ("b", -25, -25, -25, 1),
+            ("c", 44, 44, 44, 1)
+        ]
+    );
+
+    #[test]
+    fn merge_stations_combines_odd_amount_of_maps() {
+        let maps = (0..5)
+            .map(|i| {
+                aggregate(
+                    format!("a;{i}.0\nb{i};1.0\n").as_bytes(),
+                    ParseOptions::default(),
+                )
+                .unwrap()
+                .stations
+            })
+            .collect();
+        let merged = merge_stations(maps);
+        assert_eq!(merged.len(), 6);
+        let a = &merged[&b"a".to_vec()];
+        assert_eq!((a.min, a.max, a.sum, a.count), (0, 40, 100, 5));
+        assert!(merge_stations::<StationMap>(vec![]).is_empty());
+    }
+
+    #[test]
+    fn workers_pull_all_chunks_from_queue() {
+        let file_name = "samples/measurements-10000-unique-keys.txt";
+        let file_len = std::fs::metadata(file_name).unwrap().len() as usize;
+        let aggregate_with = |chunk_count, threads| {
+            let f = BufReader::new(File::open(file_name).unwrap());
+            let chunks = chunk_le_file(f, file_len, chunk_count);
+            let job = Job {
+                file_name,
+                chunks: &chunks,
+                threads,
+                options: ParseOptions::default(),
+                reader: ReaderKind::Blocking,
+                buf_len: MIN_BUF_LEN,
+                progress: None,
+                checkpoint: None,
+                base: None,
+            };
+            let maps = run_workers::<StationMap, _>(&job, Swar)
+                .into_iter()
+                .map(|a| a.unwrap().0.stations)
+                .collect::<Vec<_>>();
+            assert!(maps.len() <= threads);
+            let mut res = merge_stations(maps)
+                .into_iter()
+                .map(|(k, v)| (k, v.min, v.max, v.sum, v.count))
+                .collect::<Vec<_>>();
+            res.sort_unstable();
+            res
+        };
+        let expected = aggregate_with(1, 1);
+        assert_eq!(expected.len(), 10000);
+        assert_eq!(aggregate_with(100, 3), expected);
+        assert_eq!(aggregate_with(7, 16), expected);
+    }
+
+    #[test]
+    fn worker_panic_stops_progress_reporter() {
+        let path = std::env::temp_dir().join(format!("brc-panic-{}.txt", std::process::id()));
+        std::fs::write(&path, b"ok;1.0\nbad;12\n").unwrap();
+        let file_name = path.display().to_string();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let file_len = std::fs::metadata(&file_name).unwrap().len() as usize;
+            let f = BufReader::new(File::open(&file_name).unwrap());
+            let chunks = chunk_le_file(f, file_len, 1);
+            let progress = Progress::new(file_len);
+            let job = Job {
+                file_name: &file_name,
+                chunks: &chunks,
+                threads: 1,
+                options: ParseOptions::default(),
+                reader: ReaderKind::Blocking,
+                buf_len: MIN_BUF_LEN,
+                progress: Some(&progress),
+                checkpoint: None,
+                base: None,
+            };
+            let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                run_workers::<StationMap, _>(&job, Swar)
+            }));
+            tx.send(res.is_err()).unwrap();
+        });
+        // the panic of the worker has to come through instead of the run hanging
+        let panicked = rx.recv_timeout(std::time::Duration::from_secs(10));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(panicked, Ok(true));
+    }
+    #[test]
+    fn resume_skips_completed_chunks() {
+        let file_name = "samples/measurements-10000-unique-keys.txt";
+        let input = std::fs::read(file_name).unwrap();
+        let chunks = chunk_le_file(Cursor::new(&input), input.len(), 8);
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("brc-resume-{}", std::process::id()));
+        let odd_chunks = dir.join(format!("brc-resume-odd-{}.txt", std::process::id()));
+        let bytes = |c: &Chunk| &input[c.start_point as usize..][..c.len];
+        std::fs::write(
+            &odd_chunks,
+            chunks
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .flat_map(bytes)
+                .copied()
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let resume = |completed: Part| {
+            let checkpointer = Checkpointer::new(
+                &path,
+                Duration::ZERO,
+                checkpoint_input(file_name, ParseOptions::default()),
+                chunks.clone(),
+                Part::default(),
+                1,
+            );
+            checkpointer.save(0, completed).unwrap();
+            let res = calc(&Config {
+                file_name: file_name.into(),
+                checkpoint: Some(path.display().to_string()),
+                resume: true,
+                threads: Some(2.try_into().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+            assert!(!path.exists());
+            res
+        };
+        let done = chunks
+            .iter()
+            .step_by(2)
+            .map(|c| c.start_point)
+            .collect::<Vec<_>>();
+        // the even chunks are marked done without their rows, so only the odd ones may be in the result
+        let only_odd = resume(Part {
+            done: done.clone(),
+            aggregated: Aggregated::default(),
+        });
+        let expected = calc(&Config {
+            file_name: odd_chunks.display().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(only_odd, expected);
+
+        let mut completed = Aggregated::<StationMap>::default();
+        for c in chunks.iter().step_by(2) {
+            let mut buf = vec![0; MIN_BUF_LEN];
+            aggregate_measurements(
+                &mut completed,
+                bytes(c),
+                "input",
+                &mut buf,
+                ParseOptions::default(),
+                Swar,
+                None,
+            )
+            .unwrap();
+        }
+        let all = resume(Part {
+            done,
+            aggregated: Aggregated {
+                stations: completed.stations.into_iter().collect(),
+                skipped_rows: 0,
+                rows: completed.rows,
+            },
+        });
+        assert_eq!(
+            all,
+            std::fs::read(file_name.replace(".txt", ".out")).unwrap()
+        );
+        std::fs::remove_file(odd_chunks).unwrap();
+    }
+    fn checkpoint_input(file_name: impl AsRef<std::path::Path>, options: ParseOptions) -> Input {
+        Input::new(&File::open(file_name).unwrap(), options).unwrap()
+    }
+    #[test]
+    fn resume_refuses_other_inputs_and_options() {
+        let file_name = "samples/measurements-10000-unique-keys.txt";
+        let mut input = std::fs::read(file_name).unwrap();
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("brc-resume-other-{}", std::process::id()));
+        let changed = dir.join(format!("brc-resume-changed-{}.txt", std::process::id()));
+        // the same length, one digit different
+        let digit = input.iter().position(u8::is_ascii_digit).unwrap();
+        input[digit] = if input[digit] == b'1' { b'2' } else { b'1' };
+        std::fs::write(&changed, &input).unwrap();
+        let checkpointer = Checkpointer::new(
+            &path,
+            Duration::ZERO,
+            checkpoint_input(file_name, ParseOptions::default()),
+            chunk_le_file(Cursor::new(&input), input.len(), 4),
+            Part::default(),
+            1,
+        );
+        checkpointer.save(0, Part::default()).unwrap();
+        let resume = |file_name: &str, max_name_len| {
+            calc(&Config {
+                file_name: file_name.into(),
+                checkpoint: Some(path.display().to_string()),
+                resume: true,
+                max_name_len,
+                ..Default::default()
+            })
+        };
+        for res in [
+            resume(&changed.display().to_string(), 100),
+            resume(file_name, 50),
+        ] {
+            assert!(matches!(res, Err(Error::Checkpoint { .. })));
+        }
+        // the checkpoint is left for a run that matches it
+        assert!(resume(file_name, 100).is_ok());
+        assert!(!path.exists());
+        std::fs::remove_file(changed).unwrap();
+    }
+
+    #[test]
+    fn state_aggregates_only_appended_lines() {
+        let file_name = "samples/measurements-10000-unique-keys.txt";
+        let input = std::fs::read(file_name).unwrap();
+        let half = input[..input.len() / 2]
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .unwrap()
+            + 1;
+        let dir = std::env::temp_dir();
+        let appended = dir.join(format!("brc-state-input-{}.txt", std::process::id()));
+        let path = dir.join(format!("brc-state-{}", std::process::id()));
+        let config = Config {
+            file_name: appended.display().to_string(),
+            state: Some(path.display().to_string()),
+            threads: Some(2.try_into().unwrap()),
+            ..Default::default()
+        };
+        std::fs::write(&appended, &input[..half]).unwrap();
+        calc(&config).unwrap();
+        std::fs::write(&appended, &input).unwrap();
+        let expected = std::fs::read(file_name.replace(".txt", ".out")).unwrap();
+        assert_eq!(calc(&config).unwrap(), expected);
+
+        // a station only in the index shows that the prefix was not read again
+        let mut index = incremental::Index::load(&path).unwrap();
+        assert_eq!(index.len, input.len() as u64);
+        let only_in_index = WeatherStationStats {
+            min: 0,
+            max: 0,
+            sum: 0,
+            count: 1,
+        };
+        index
+            .aggregated
+            .stations
+            .push((b"only in index".to_vec(), only_in_index));
+        incremental::Index::save(&path, &config.file_name, index.len, &index.aggregated).unwrap();
+        let from_index = String::from_utf8(calc(&config).unwrap()).unwrap();
+        assert!(from_index.contains("only in index=0.0/0.0/0.0"));
+
+        // a changed prefix is aggregated again from the start
+        let mut changed = input.clone();
+        changed[0] = b'#';
+        std::fs::write(&appended, &changed).unwrap();
+        let full_pass = String::from_utf8(calc(&config).unwrap()).unwrap();
+        assert!(!full_pass.contains("only in index"));
+        assert!(full_pass.starts_with("{#"));
+
+        std::fs::remove_file(appended).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn with_long_line(name_len: usize, trailing_newline: bool) -> Vec<u8> {
+        let mut input = b"a;1.0\n".to_vec();
+        input.extend(std::iter::repeat_n(b'x', name_len));
+        input.extend(b";2.0\nb;3.0\n");
+        input.extend(std::iter::repeat_n(b'y', name_len));
+        input.extend(b";4.0");
+        if trailing_newline {
+            input.push(b'\n');
+        }
+        input
+    }
+    macro_rules! tst_long_lines {
+        ($func:ident,$name_len:expr,$trailing_newline:expr) => {
+            #[test]
+            fn $func() {
+                let input = with_long_line($name_len, $trailing_newline);
+                let err = aggregate(&input[..], ParseOptions::default());
+                assert!(matches!(
+                    err,
+                    Err(Error::NameTooLong {
+                        max_name_len: 100,
+                        ..
+                    })
+                ));
+                let options = ParseOptions {
+                    long_lines: LongLinePolicy::Skip,
+                    ..Default::default()
+                };
+                let res = aggregate(&input[..], options).unwrap();
+                assert_eq!(res.skipped_rows, 2);
+                let mut names = res.stations.into_keys().collect::<Vec<_>>();
+                names.sort_unstable();
+                assert_eq!(names, vec![b"a".to_vec(), b"b".to_vec()]);
+            }
+        };
+    }
+    tst_long_lines!(long_lines_just_over_limit, 101, true);
+    tst_long_lines!(long_lines_just_over_limit_without_newline, 101, false);
+    tst_long_lines!(long_lines_over_read_buffer, MIN_BUF_LEN + 1000, true);
+    tst_long_lines!(
+        long_lines_over_read_buffer_without_newline,
+        MIN_BUF_LEN + 1000,
+        false
+    );
+    #[test]
+    fn long_lines_at_limit_are_accepted() {
+        let input = with_long_line(100, true);
+        let res = aggregate(&input[..], ParseOptions::default()).unwrap();
+        assert_eq!(res.skipped_rows, 0);
+        assert_eq!(res.stations.len(), 4);
+    }
+    #[test]
+    fn chunks_stay_line_aligned_with_long_lines() {
+        let input = with_long_line(10_000, false);
+        let chunks = chunk_le_file(Cursor::new(&input), input.len(), 16);
+        for c in &chunks {
+            let start = c.start_point as usize;
+            assert!(start == 0 || input[start - 1] == b'\n');
+        }
+        let last = chunks.last().unwrap();
+        assert_eq!(last.start_point as usize + last.len, input.len());
+    }
+
     macro_rules! tst {
         ($func:ident,$file_name:expr) => {
             #[test]
             fn $func() {
                 println!($file_name);
                 let res = read_to_string(format!("{}.out", $file_name)).unwrap();
-                for (expected, val) in res
-                    .split(",")
-                    .zip(calc(Some(format!("{}.txt", $file_name))).split(","))
-                {
-                    assert_eq!(val.trim(), expected.trim());
+                for table in [TableKind::Std, TableKind::Open] {
+                    for (scanner, parser) in [
+                        (ScanKind::Bytewise, ParserKind::Match),
+                        (ScanKind::Bytewise, ParserKind::Swar),
+                        (ScanKind::Swar, ParserKind::Match),
+                        (ScanKind::Simd, ParserKind::Swar),
+                    ] {
+                        let output = calc(&Config {
+                            file_name: format!("{}.txt", $file_name),
+                            table,
+                            scanner,
+                            parser,
+                            ..Default::default()
+                        })
+                        .unwrap();
+                        let differences = diff::compare(
+                            &diff::read(res.as_bytes()).unwrap(),
+                            &diff::read(&output).unwrap(),
+                            0.0,
+                        );
+                        assert!(differences.is_empty(), "{differences:#?}");
+                    }
                 }
             }
         };
@@ -330,6 +1844,11 @@ mod tests {
     tst!(measurements_20, "samples/measurements-20");
     tst!(measurements_3, "samples/measurements-3");
     tst!(measurements_boundaries, "samples/measurements-boundaries");
+    tst!(measurements_crlf, "samples/measurements-crlf");
+    tst!(
+        measurements_no_trailing_newline,
+        "samples/measurements-no-trailing-newline"
+    );
     tst!(
         measurements_complex_utf8,
         "samples/measurements-complex-utf8"
@@ -0,0 +1,88 @@
+//! `brc-rs merge`: combines results of runs over different parts of the data, written with `--format binary|json`.
+use std::collections::HashMap;
+
+use crate::{output, output::OutputFormat, serialize, text, usage, Error, WeatherStationStats};
+
+pub fn merge(mut args: impl Iterator<Item = String>) -> Result<Vec<u8>, Error> {
+    let mut format = OutputFormat::default();
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = OutputFormat::from_arg(args.next()),
+            flag if flag.starts_with("--") => usage(&format!("unknown option for merge {flag}")),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage("merge expects at least one file");
+    }
+    let mut merged: HashMap<String, WeatherStationStats> = HashMap::new();
+    for path in files {
+        let stations = std::fs::read(&path)
+            .and_then(|bytes| {
+                serialize::read_results(&bytes).map_err(|error| match text::parse(&bytes) {
+                    Ok(_) => serialize::invalid_data(
+                        "text results have no sum or count to merge, write them with --format json or binary",
+                    ),
+                    Err(_) => error,
+                })
+            })
+            .map_err(|error| Error::Results { path, error })?;
+        for (name, stats) in stations {
+            match merged.get_mut(&name) {
+                Some(s) => *s = stats + s,
+                None => {
+                    merged.insert(name, stats);
+                }
+            }
+        }
+    }
+    let mut stations = merged.into_iter().collect::<Vec<_>>();
+    stations.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(output::render(format, &stations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge;
+    use crate::{calc, output::OutputFormat, Config};
+
+    #[test]
+    fn merged_halves_match_whole_file() {
+        let file_name = "samples/measurements-10000-unique-keys.txt";
+        let input = std::fs::read(file_name).unwrap();
+        let mid = input[..input.len() / 2]
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .unwrap()
+            + 1;
+        let dir = std::env::temp_dir();
+        let mut results = vec![];
+        for (i, (half, format)) in [
+            (&input[..mid], OutputFormat::Binary),
+            (&input[mid..], OutputFormat::Json),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let path = dir.join(format!("brc-merge-{}-{i}", std::process::id()));
+            std::fs::write(&path, half).unwrap();
+            let output = calc(&Config {
+                file_name: path.display().to_string(),
+                format,
+                ..Default::default()
+            })
+            .unwrap();
+            std::fs::write(&path, output).unwrap();
+            results.push(path.display().to_string());
+        }
+        let merged = merge(results.iter().cloned()).unwrap();
+        assert_eq!(
+            merged,
+            std::fs::read(file_name.replace(".txt", ".out")).unwrap()
+        );
+        for path in results {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Turning the raw station name bytes into the names that are printed.
+//!
+//! The aggregation itself never looks at the names as anything other than bytes, as UTF-8 validation for every row
+//! is expensive. Instead, the validation is done once per unique station after all the rows have been aggregated.
+use std::collections::HashMap;
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::{Error, WeatherStationStats};
+
+/// What to do with station names that are not valid UTF-8
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidUtf8Policy {
+    /// Fail the whole run
+    Reject,
+    /// Keep the valid parts, write invalid bytes as `\xNN` and backslashes as `\\`. Distinct names stay distinct
+    Escape,
+    /// Replace invalid bytes with U+FFFD like `String::from_utf8_lossy`, which can merge distinct names
+    #[default]
+    Lossy,
+}
+
+impl InvalidUtf8Policy {
+    pub fn parse(s: &str) -> Option<InvalidUtf8Policy> {
+        match s {
+            "reject" => Some(InvalidUtf8Policy::Reject),
+            "escape" => Some(InvalidUtf8Policy::Escape),
+            "lossy" => Some(InvalidUtf8Policy::Lossy),
+            _ => None,
+        }
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InvalidUtf8Policy::Reject => "reject",
+            InvalidUtf8Policy::Escape => "escape",
+            InvalidUtf8Policy::Lossy => "lossy",
+        }
+    }
+}
+
+/// Names resolved from the raw station map, merged if multiple raw names end up with the same name
+pub struct ResolvedNames {
+    pub stations: Vec<(String, WeatherStationStats)>,
+    /// Count of rows whose station name was not valid UTF-8
+    pub invalid_rows: usize,
+}
+
+pub fn resolve(
+    stations: impl IntoIterator<Item = (Vec<u8>, WeatherStationStats)>,
+    policy: InvalidUtf8Policy,
+    nfc: bool,
+) -> Result<ResolvedNames, Error> {
+    let mut invalid_rows = 0;
+    let mut res = HashMap::<String, WeatherStationStats>::new();
+    for (raw, stats) in stations {
+        let name = match String::from_utf8(raw) {
+            // valid names are escaped too, or `a\xFF` written out would be the same as `a` followed by 0xFF
+            Ok(name) if policy == InvalidUtf8Policy::Escape && name.contains('\\') => {
+                name.replace('\\', "\\\\")
+            }
+            Ok(name) => name,
+            Err(e) => {
+                invalid_rows += stats.count;
+                match policy {
+                    InvalidUtf8Policy::Reject => {
+                        return Err(Error::InvalidStationName {
+                            name: escape(e.as_bytes()),
+                            rows: stats.count,
+                        })
+                    }
+                    InvalidUtf8Policy::Escape => escape(e.as_bytes()),
+                    InvalidUtf8Policy::Lossy => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                }
+            }
+        };
+        // the quick check is a lot cheaper than normalizing, and most names are already in NFC
+        let name = if nfc && !is_nfc(&name) {
+            name.nfc().collect()
+        } else {
+            name
+        };
+        match res.get_mut(&name) {
+            Some(existing) => *existing = stats + existing,
+            None => {
+                res.insert(name, stats);
+            }
+        }
+    }
+    Ok(ResolvedNames {
+        stations: res.into_iter().collect(),
+        invalid_rows,
+    })
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        res.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for b in chunk.invalid() {
+            res.push_str(&format!("\\x{b:02X}"));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, InvalidUtf8Policy};
+    use crate::{Error, WeatherStationStats};
+
+    fn stats(measurement: i64, count: usize) -> WeatherStationStats {
+        WeatherStationStats {
+            min: measurement,
+            max: measurement,
+            sum: measurement * count as i64,
+            count,
+        }
+    }
+    fn resolved(
+        stations: Vec<(&[u8], WeatherStationStats)>,
+        policy: InvalidUtf8Policy,
+        nfc: bool,
+    ) -> (Vec<(String, usize)>, usize) {
+        let res = resolve(
+            stations.into_iter().map(|(k, v)| (k.to_vec(), v)),
+            policy,
+            nfc,
+        )
+        .unwrap();
+        let mut names = res
+            .stations
+            .into_iter()
+            .map(|(k, v)| (k, v.count))
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        (names, res.invalid_rows)
+    }
+
+    #[test]
+    fn lossy_merges_distinct_invalid_names() {
+        let (names, invalid_rows) = resolved(
+            vec![
+                (b"a\xff", stats(10, 2)),
+                (b"a\xfe", stats(20, 3)),
+                (b"b", stats(0, 1)),
+            ],
+            InvalidUtf8Policy::Lossy,
+            false,
+        );
+        assert_eq!(names, vec![("a\u{FFFD}".into(), 5), ("b".into(), 1)]);
+        assert_eq!(invalid_rows, 5);
+    }
+    #[test]
+    fn escape_keeps_distinct_invalid_names() {
+        let (names, invalid_rows) = resolved(
+            vec![
+                (b"a\xff", stats(10, 2)),
+                (b"a\xfe", stats(20, 3)),
+                (b"b", stats(0, 1)),
+            ],
+            InvalidUtf8Policy::Escape,
+            false,
+        );
+        assert_eq!(
+            names,
+            vec![("a\\xFE".into(), 3), ("a\\xFF".into(), 2), ("b".into(), 1)]
+        );
+        assert_eq!(invalid_rows, 5);
+    }
+    #[test]
+    fn escape_keeps_literal_escapes_apart() {
+        let (names, invalid_rows) = resolved(
+            vec![
+                (b"a\\xFF", stats(10, 2)),
+                (b"a\xff", stats(20, 3)),
+                (b"b\\\xff", stats(0, 1)),
+            ],
+            InvalidUtf8Policy::Escape,
+            false,
+        );
+        assert_eq!(
+            names,
+            vec![
+                ("a\\\\xFF".into(), 2),
+                ("a\\xFF".into(), 3),
+                ("b\\\\\\xFF".into(), 1)
+            ]
+        );
+        assert_eq!(invalid_rows, 4);
+    }
+    #[test]
+    fn reject_fails_on_invalid_name() {
+        let res = resolve(
+            vec![
+                (b"ok".to_vec(), stats(0, 1)),
+                (b"a\xff".to_vec(), stats(10, 2)),
+            ],
+            InvalidUtf8Policy::Reject,
+            false,
+        );
+        assert!(matches!(
+            res,
+            Err(Error::InvalidStationName { name, rows: 2 }) if name == "a\\xFF"
+        ));
+    }
+    #[test]
+    fn nfc_merges_visually_identical_names() {
+        let decomposed = "Abe\u{301}che\u{301}";
+        let (names, invalid_rows) = resolved(
+            vec![
+                (decomposed.as_bytes(), stats(10, 2)),
+                ("Abéché".as_bytes(), stats(20, 3)),
+            ],
+            InvalidUtf8Policy::Lossy,
+            true,
+        );
+        assert_eq!(names, vec![("Abéché".into(), 5)]);
+        assert_eq!(invalid_rows, 0);
+    }
+    #[test]
+    fn without_nfc_names_stay_distinct() {
+        let decomposed = "Abe\u{301}che\u{301}";
+        let (names, _) = resolved(
+            vec![
+                (decomposed.as_bytes(), stats(10, 2)),
+                ("Abéché".as_bytes(), stats(20, 3)),
+            ],
+            InvalidUtf8Policy::Lossy,
+            false,
+        );
+        assert_eq!(names.len(), 2);
+    }
+}
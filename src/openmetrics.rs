@@ -0,0 +1,136 @@
+//! OpenMetrics text exposition of the stations, for scraping the results with Prometheus.
+//!
+//! Every station is a sample of each metric family with the name as the `station` label. Temperatures are gauges in
+//! degrees Celsius, the sum included as it goes down with negative measurements. The count of measurements is a counter.
+use std::fmt::Write;
+
+use crate::WeatherStationStats;
+
+/// The `Content-Type` of the exposition over HTTP
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// One metric family, with a sample for every station
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&WeatherStationStats) -> String,
+}
+const FAMILIES: [Family; 5] = [
+    Family {
+        name: "min_celsius",
+        kind: "gauge",
+        help: "Lowest measurement",
+        value: |s| tenths(s.min),
+    },
+    Family {
+        name: "max_celsius",
+        kind: "gauge",
+        help: "Highest measurement",
+        value: |s| tenths(s.max),
+    },
+    Family {
+        name: "mean_celsius",
+        kind: "gauge",
+        help: "Mean of the measurements",
+        value: |s| s.mean().to_string(),
+    },
+    Family {
+        name: "sum_celsius",
+        kind: "gauge",
+        help: "Sum of the measurements",
+        value: |s| tenths(s.sum),
+    },
+    Family {
+        name: "measurements",
+        kind: "counter",
+        help: "Number of measurements",
+        value: |s| s.count.to_string(),
+    },
+];
+
+/// `stations` must be sorted by name, so the samples are in the same order on every scrape
+pub fn render(stations: &[(String, WeatherStationStats)]) -> String {
+    let mut res = String::new();
+    for Family {
+        name,
+        kind,
+        help,
+        value,
+    } in FAMILIES
+    {
+        let name = format!("brc_station_{name}");
+        writeln!(res, "# TYPE {name} {kind}").unwrap();
+        if name.ends_with("_celsius") {
+            writeln!(res, "# UNIT {name} celsius").unwrap();
+        }
+        writeln!(res, "# HELP {name} {help} of the station.").unwrap();
+        let suffix = if kind == "counter" { "_total" } else { "" };
+        for (station, stats) in stations {
+            writeln!(
+                res,
+                "{name}{suffix}{{station=\"{}\"}} {}",
+                escape_label(station),
+                value(stats)
+            )
+            .unwrap();
+        }
+    }
+    res.push_str("# EOF\n");
+    res
+}
+/// Tenths of a degree as degrees, exactly
+fn tenths(n: i64) -> String {
+    let sign = if n < 0 { "-" } else { "" };
+    format!("{sign}{}.{}", n.unsigned_abs() / 10, n.unsigned_abs() % 10)
+}
+/// Label values are quoted, so backslashes, double quotes and newlines have to be escaped
+fn escape_label(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '"' => res.push_str("\\\""),
+            '\n' => res.push_str("\\n"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::WeatherStationStats;
+
+    #[test]
+    fn renders_escaped_exposition() {
+        let stats = WeatherStationStats {
+            min: -5,
+            max: 251,
+            sum: 456,
+            count: 4,
+        };
+        let stations = [
+            ("Abha".to_string(), stats),
+            ("a \"quoted\\\"\nname".to_string(), stats),
+        ];
+        let res = render(&stations);
+        assert!(res.starts_with(
+            "# TYPE brc_station_min_celsius gauge\n\
+             # UNIT brc_station_min_celsius celsius\n\
+             # HELP brc_station_min_celsius Lowest measurement of the station.\n\
+             brc_station_min_celsius{station=\"Abha\"} -0.5\n\
+             brc_station_min_celsius{station=\"a \\\"quoted\\\\\\\"\\nname\"} -0.5\n"
+        ));
+        assert!(res.contains("brc_station_mean_celsius{station=\"Abha\"} 11.4\n"));
+        assert!(res.contains("brc_station_sum_celsius{station=\"Abha\"} 45.6\n"));
+        assert!(res.contains(
+            "# TYPE brc_station_measurements counter\n\
+             # HELP brc_station_measurements Number of measurements of the station.\n\
+             brc_station_measurements_total{station=\"Abha\"} 4\n"
+        ));
+        assert!(res.ends_with("\"} 4\n# EOF\n"));
+        assert_eq!(res.lines().filter(|l| !l.starts_with('#')).count(), 10);
+    }
+}
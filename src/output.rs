@@ -0,0 +1,59 @@
+//! Formats of the final results.
+//!
+//! `text` is the challenge output. `json` and `binary` keep the sum and count of every station instead of the
+//! rounded mean, so results of different runs can be combined with `brc-rs merge`. `openmetrics` is for scraping,
+//! `arrow` (IPC file) and `parquet` for analytics, the latter two only with the `arrow` feature.
+use crate::{openmetrics, serialize, text, usage, WeatherStationStats};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Binary,
+    OpenMetrics,
+    Arrow,
+    Parquet,
+}
+impl OutputFormat {
+    pub fn parse(s: &str) -> Option<OutputFormat> {
+        match s {
+            "text" => Some(OutputFormat::Text),
+            "json" => Some(OutputFormat::Json),
+            "binary" => Some(OutputFormat::Binary),
+            "openmetrics" => Some(OutputFormat::OpenMetrics),
+            "arrow" => Some(OutputFormat::Arrow),
+            "parquet" => Some(OutputFormat::Parquet),
+            _ => None,
+        }
+    }
+    /// Parses the value of `--format`, exiting with the usage if the format is unknown or not in this build
+    pub fn from_arg(arg: Option<String>) -> OutputFormat {
+        let Some(format) = arg.as_deref().and_then(OutputFormat::parse) else {
+            usage("--format expects one of: text, json, binary, openmetrics, arrow, parquet");
+        };
+        if matches!(format, OutputFormat::Arrow | OutputFormat::Parquet) && !cfg!(feature = "arrow")
+        {
+            usage("--format arrow and parquet need a build with the arrow feature");
+        }
+        format
+    }
+}
+
+/// `stations` must be sorted by name
+pub fn render(format: OutputFormat, stations: &[(String, WeatherStationStats)]) -> Vec<u8> {
+    match format {
+        OutputFormat::Text => text::write(stations).into_bytes(),
+        OutputFormat::Json => serialize::write_json(stations),
+        OutputFormat::Binary => serialize::write_binary(stations),
+        OutputFormat::OpenMetrics => openmetrics::render(stations).into_bytes(),
+        #[cfg(feature = "arrow")]
+        OutputFormat::Arrow => crate::arrow::write_ipc(stations),
+        #[cfg(feature = "arrow")]
+        OutputFormat::Parquet => crate::arrow::write_parquet(stations),
+        #[cfg(not(feature = "arrow"))]
+        OutputFormat::Arrow | OutputFormat::Parquet => {
+            unreachable!("rejected by OutputFormat::from_arg")
+        }
+    }
+}
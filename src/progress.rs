@@ -0,0 +1,92 @@
+//! Progress of a run on stderr, for inputs large enough that waiting for the result without feedback gets old.
+use std::{
+    io::{IsTerminal, Write},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+const REFRESH: Duration = Duration::from_millis(200);
+
+pub struct Progress {
+    total: usize,
+    /// Bytes read by all workers so far, updated once per buffer fill
+    read: AtomicUsize,
+    start: Instant,
+}
+impl Progress {
+    /// `None` if stderr is not a terminal, as the carriage returns would only clutter a log file
+    pub fn for_stderr(total: usize) -> Option<Progress> {
+        std::io::stderr()
+            .is_terminal()
+            .then(|| Progress::new(total))
+    }
+    pub fn new(total: usize) -> Progress {
+        Progress {
+            total,
+            read: AtomicUsize::new(0),
+            start: Instant::now(),
+        }
+    }
+    #[inline]
+    pub fn add(&self, bytes: usize) {
+        self.read.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Redraws the progress line every `REFRESH` until `done` is set, and once more after it.
+    /// Unpark the thread after setting `done` to not wait for the rest of the refresh interval
+    pub fn report_until(&self, done: &AtomicBool) {
+        let mut stderr = std::io::stderr();
+        loop {
+            let finished = done.load(Ordering::Relaxed);
+            let line = self.line(self.read.load(Ordering::Relaxed), self.start.elapsed());
+            let end = if finished { "\n" } else { "" };
+            // errors writing the progress are not worth failing the run for
+            let _ = write!(stderr, "\r{line}\x1b[K{end}");
+            let _ = stderr.flush();
+            if finished {
+                break;
+            }
+            thread::park_timeout(REFRESH);
+        }
+    }
+    fn line(&self, read: usize, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64();
+        let per_sec = if secs > 0.0 { read as f64 / secs } else { 0.0 };
+        let percent = if self.total > 0 {
+            read as f64 / self.total as f64 * 100.0
+        } else {
+            100.0
+        };
+        let eta = if per_sec > 0.0 {
+            format!("{:.0} s", self.total.saturating_sub(read) as f64 / per_sec)
+        } else {
+            String::from("?")
+        };
+        format!(
+            "{percent:5.1}% {:.0}/{:.0} MB, {:.1} MB/s, ETA {eta}",
+            read as f64 / 1_000_000.0,
+            self.total as f64 / 1_000_000.0,
+            per_sec / 1_000_000.0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Progress;
+
+    #[test]
+    fn progress_line_has_eta_and_throughput() {
+        let progress = Progress::new(300_000_000);
+        assert_eq!(
+            progress.line(100_000_000, Duration::from_secs(2)),
+            " 33.3% 100/300 MB, 50.0 MB/s, ETA 4 s"
+        );
+        assert_eq!(
+            progress.line(0, Duration::ZERO),
+            "  0.0% 0/300 MB, 0.0 MB/s, ETA ?"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Reading the chunks of the input file.
+//!
+//! The default is a blocking `read` into the buffer of `aggregate_measurements`, which leaves the worker idle
+//! whenever the kernel has to go to the disk. With the `io-uring` feature on Linux, `uring` keeps several reads
+//! per worker in flight into buffers registered with the ring, so the next buffers are read while the current one is parsed.
+//! `mmap` maps the file and copies from the mapping, leaving the reading to page faults and the kernel's readahead.
+//! It is there to compare the other two against, the parsing still goes through the same buffer.
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Take},
+};
+
+use memmap2::Mmap;
+
+/// How the workers read their chunks, from the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReaderKind {
+    #[default]
+    Blocking,
+    Uring,
+    Mmap,
+}
+impl ReaderKind {
+    pub fn parse(s: &str) -> Option<ReaderKind> {
+        match s {
+            "blocking" => Some(ReaderKind::Blocking),
+            "uring" => Some(ReaderKind::Uring),
+            "mmap" => Some(ReaderKind::Mmap),
+            _ => None,
+        }
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReaderKind::Blocking => "blocking",
+            ReaderKind::Uring => "uring",
+            ReaderKind::Mmap => "mmap",
+        }
+    }
+    /// Checks that the reader can be used, io_uring can be missing from the build or disabled in the kernel.
+    /// Sets up a ring like a worker does, registering the buffers can fail on its own under a low memlock limit
+    pub fn check(self) -> io::Result<()> {
+        match self {
+            ReaderKind::Blocking | ReaderKind::Mmap => Ok(()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ReaderKind::Uring => uring::UringReader::setup(uring::DEPTH, uring::BUF_LEN).map(drop),
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            ReaderKind::Uring => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without the io-uring feature",
+            )),
+        }
+    }
+}
+
+/// Reads one chunk of the file at a time, each worker has its own
+pub enum ChunkReader {
+    Blocking(Take<File>),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Box<uring::UringReader>),
+    Mmap {
+        map: Mmap,
+        pos: usize,
+        end: usize,
+    },
+}
+impl ChunkReader {
+    pub fn open(file_name: &str, kind: ReaderKind) -> io::Result<ChunkReader> {
+        let f = File::open(file_name)?;
+        match kind {
+            ReaderKind::Blocking => Ok(ChunkReader::Blocking(f.take(0))),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ReaderKind::Uring => Ok(ChunkReader::Uring(Box::new(uring::UringReader::new(f)?))),
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            ReaderKind::Uring => kind.check().map(|_| unreachable!()),
+            ReaderKind::Mmap => Ok(ChunkReader::Mmap {
+                // SAFETY: the mapping is only read, like every reader this one expects that the input is not
+                // truncated while it is read. With a mapping that is a SIGBUS instead of a short read
+                map: unsafe { Mmap::map(&f)? },
+                pos: 0,
+                end: 0,
+            }),
+        }
+    }
+    /// Starts reading `len` bytes from `start`, whatever was left of the previous chunk is dropped
+    pub fn start_chunk(&mut self, start: u64, len: u64) -> io::Result<()> {
+        match self {
+            ChunkReader::Blocking(f) => {
+                f.get_mut().seek(SeekFrom::Start(start))?;
+                f.set_limit(len);
+                Ok(())
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ChunkReader::Uring(r) => r.start_chunk(start, len),
+            ChunkReader::Mmap { map, pos, end } => {
+                // clamped like a read past the end of the file, to the length when the file was mapped
+                *pos = (start as usize).min(map.len());
+                *end = (start + len).min(map.len() as u64) as usize;
+                #[cfg(unix)]
+                map.advise_range(memmap2::Advice::Sequential, *pos, *end - *pos)?;
+                Ok(())
+            }
+        }
+    }
+}
+impl Read for ChunkReader {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ChunkReader::Blocking(f) => f.read(buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ChunkReader::Uring(r) => r.read(buf),
+            ChunkReader::Mmap { map, pos, end } => {
+                let n = (*end - *pos).min(buf.len());
+                buf[..n].copy_from_slice(&map[*pos..*pos + n]);
+                *pos += n;
+                Ok(n)
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+    use std::{
+        collections::VecDeque,
+        fs::File,
+        io::{self, Read},
+        os::{fd::AsRawFd, unix::fs::FileExt},
+    };
+
+    use io_uring::{opcode, types, IoUring};
+
+    /// Reads in flight per worker. Four is enough to keep a NVMe drive busy while the worker parses
+    pub const DEPTH: usize = 4;
+    pub const BUF_LEN: usize = 256 * 1024;
+
+    pub struct UringReader {
+        // dropped before the buffers registered with it
+        ring: IoUring,
+        file: File,
+        bufs: Vec<Box<[u8]>>,
+        /// Offset and length of the last read submitted into each buffer
+        requested: Vec<(u64, usize)>,
+        /// Bytes read into each buffer, or the errno of the failed read. `None` while the read is in flight
+        filled: Vec<Option<Result<usize, i32>>>,
+        /// Buffers with a read in flight or data left to copy, in file order
+        queue: VecDeque<usize>,
+        /// How much of the front buffer of `queue` has been copied out already
+        pos: usize,
+        /// Offset of the next read to submit
+        next: u64,
+        end: u64,
+    }
+    impl UringReader {
+        pub fn new(file: File) -> io::Result<UringReader> {
+            UringReader::with_buffers(file, DEPTH, BUF_LEN)
+        }
+        pub fn with_buffers(file: File, depth: usize, buf_len: usize) -> io::Result<UringReader> {
+            let (ring, bufs) = UringReader::setup(depth, buf_len)?;
+            Ok(UringReader {
+                ring,
+                file,
+                bufs,
+                requested: vec![(0, 0); depth],
+                filled: vec![Some(Ok(0)); depth],
+                queue: VecDeque::with_capacity(depth),
+                pos: 0,
+                next: 0,
+                end: 0,
+            })
+        }
+        /// The ring and the buffers registered with it, without a file to read yet
+        pub fn setup(depth: usize, buf_len: usize) -> io::Result<(IoUring, Vec<Box<[u8]>>)> {
+            let ring = IoUring::new(depth as u32)?;
+            let mut bufs: Vec<Box<[u8]>> = (0..depth).map(|_| vec![0; buf_len].into()).collect();
+            let iovecs = bufs
+                .iter_mut()
+                .map(|b| libc::iovec {
+                    iov_base: b.as_mut_ptr().cast(),
+                    iov_len: b.len(),
+                })
+                .collect::<Vec<_>>();
+            // SAFETY: the buffers are on the heap, so they do not move with the reader,
+            // and they are only freed after the ring is dropped and no read is in flight (see `Drop`)
+            unsafe { ring.submitter().register_buffers(&iovecs)? };
+            Ok((ring, bufs))
+        }
+        pub fn start_chunk(&mut self, start: u64, len: u64) -> io::Result<()> {
+            self.drain()?;
+            self.next = start;
+            self.end = start + len;
+            for idx in 0..self.bufs.len() {
+                self.submit(idx);
+            }
+            self.ring.submit()?;
+            Ok(())
+        }
+        /// Queues a read of the next part of the chunk into the buffer, unless the whole chunk is already requested
+        fn submit(&mut self, idx: usize) {
+            if self.next >= self.end {
+                return;
+            }
+            let len = (self.end - self.next).min(self.bufs[idx].len() as u64) as usize;
+            let entry = opcode::ReadFixed::new(
+                types::Fd(self.file.as_raw_fd()),
+                self.bufs[idx].as_mut_ptr(),
+                len as u32,
+                idx as u16,
+            )
+            .offset(self.next)
+            .build()
+            .user_data(idx as u64);
+            // SAFETY: the buffer is registered at `idx` and not touched again until its completion is reaped.
+            // There are never more reads in flight than buffers, which is also the size of the submission queue
+            unsafe { self.ring.submission().push(&entry).unwrap() };
+            self.requested[idx] = (self.next, len);
+            self.filled[idx] = None;
+            self.queue.push_back(idx);
+            self.next += len as u64;
+        }
+        /// Waits until the read into the buffer has completed, returns the number of bytes read
+        fn wait(&mut self, idx: usize) -> io::Result<usize> {
+            // `None` only while the read into the buffer is in flight, so there is always a completion to wait for
+            while self.filled[idx].is_none() {
+                self.ring.submit_and_wait(1)?;
+                for cqe in self.ring.completion() {
+                    let res = cqe.result();
+                    // a failed read is done with the buffer too, it must not be waited for again
+                    self.filled[cqe.user_data() as usize] =
+                        Some(if res < 0 { Err(-res) } else { Ok(res as usize) });
+                }
+            }
+            let (offset, len) = self.requested[idx];
+            let mut filled = self.filled[idx]
+                .unwrap()
+                .map_err(io::Error::from_raw_os_error)?;
+            // Short reads of regular files are rare enough to finish them with blocking reads
+            // instead of resubmitting, which would break the order of the queue
+            while filled > 0 && filled < len {
+                let n = self
+                    .file
+                    .read_at(&mut self.bufs[idx][filled..len], offset + filled as u64)?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            self.filled[idx] = Some(Ok(filled));
+            Ok(filled)
+        }
+        /// Waits for every read in flight, so the buffers can be reused or freed. Returns the first error
+        fn drain(&mut self) -> io::Result<()> {
+            let mut res = Ok(());
+            while let Some(idx) = self.queue.pop_front() {
+                // the reads after a failed one are still in flight
+                if let Err(e) = self.wait(idx) {
+                    res = res.and(Err(e));
+                }
+            }
+            self.pos = 0;
+            res
+        }
+    }
+    impl Read for UringReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(&idx) = self.queue.front() else {
+                return Ok(0);
+            };
+            let filled = self.wait(idx)?;
+            if filled == 0 {
+                // the file is shorter than the chunk, it has been truncated since it was split
+                self.drain()?;
+                return Ok(0);
+            }
+            let n = (filled - self.pos).min(buf.len());
+            buf[..n].copy_from_slice(&self.bufs[idx][self.pos..self.pos + n]);
+            self.pos += n;
+            if self.pos == filled {
+                self.queue.pop_front();
+                self.pos = 0;
+                self.submit(idx);
+                self.ring.submit()?;
+            }
+            Ok(n)
+        }
+    }
+    impl Drop for UringReader {
+        fn drop(&mut self) {
+            // the kernel must not write into the buffers after they are freed
+            let _ = self.drain();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{fs::File, io::Read};
+
+        use super::UringReader;
+
+        #[test]
+        fn uring_reads_chunks_with_reused_buffers() {
+            let file_name = "samples/measurements-10000-unique-keys.txt";
+            let expected = std::fs::read(file_name).unwrap();
+            let Ok(mut reader) = UringReader::with_buffers(File::open(file_name).unwrap(), 3, 1000)
+            else {
+                // io_uring is disabled in this kernel or sandbox
+                return;
+            };
+            for (start, len) in [(0, expected.len()), (17, 5000), (90_000, 2500), (0, 0)] {
+                reader.start_chunk(start as u64, len as u64).unwrap();
+                let mut res = vec![];
+                let mut buf = [0; 777];
+                loop {
+                    let n = reader.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    res.extend_from_slice(&buf[..n]);
+                }
+                assert_eq!(res, &expected[start..start + len]);
+            }
+            // a chunk left half read must not leak into the next one
+            reader.start_chunk(0, 10_000).unwrap();
+            reader.read_exact(&mut [0; 10]).unwrap();
+            reader.start_chunk(500, 10).unwrap();
+            let mut res = vec![];
+            reader.read_to_end(&mut res).unwrap();
+            assert_eq!(res, &expected[500..510]);
+        }
+
+        #[test]
+        fn uring_returns_failed_reads() {
+            let path = std::env::temp_dir().join(format!("brc-uring-{}", std::process::id()));
+            std::fs::write(&path, [b'x'; 5000]).unwrap();
+            // the reads fail with EBADF on a file that is only open for writing
+            let file = File::options().write(true).open(&path).unwrap();
+            let Ok(mut reader) = UringReader::with_buffers(file, 3, 1000) else {
+                return;
+            };
+            reader.start_chunk(0, 5000).unwrap();
+            assert_eq!(
+                reader.read(&mut [0; 100]).unwrap_err().raw_os_error(),
+                Some(libc::EBADF)
+            );
+            // the other reads of the chunk failed too
+            assert!(reader.start_chunk(0, 5000).is_err());
+            reader.start_chunk(0, 5000).unwrap();
+            assert!(reader.read(&mut [0; 100]).is_err());
+            // dropping has to reap the failed reads instead of waiting for them forever
+            drop(reader);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{ChunkReader, ReaderKind};
+
+    #[test]
+    fn chunk_reader_reads_only_the_chunk() {
+        let file_name = "samples/measurements-10000-unique-keys.txt";
+        let expected = std::fs::read(file_name).unwrap();
+        for kind in [ReaderKind::Blocking, ReaderKind::Uring, ReaderKind::Mmap] {
+            if kind.check().is_err() {
+                continue;
+            }
+            let mut reader = ChunkReader::open(file_name, kind).unwrap();
+            for (start, len) in [(0, expected.len()), (1234, 60_000), (expected.len() - 1, 1)] {
+                reader.start_chunk(start as u64, len as u64).unwrap();
+                let mut res = vec![];
+                reader.read_to_end(&mut res).unwrap();
+                assert_eq!(res, &expected[start..start + len], "{kind:?}");
+            }
+        }
+    }
+}
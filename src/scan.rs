@@ -0,0 +1,320 @@
+//! Finding the line ending and the separator of the next line.
+//!
+//! The original approach finds `\n` with a bytewise `position` and `parse_line` then goes backwards for the `;`.
+//! The other scanners find both in the same pass, either a word (SWAR) or a SIMD register at a time.
+//! Everything except the SSE2/AVX2 intrinsics is safe Rust, and those are only used if the CPU supports them.
+
+/// Positions in a line as returned by `Scanner::find`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delimiters {
+    /// Index of the first `\n`
+    pub newline: usize,
+    /// Index of the last `;` before the newline, `None` if the scanner does not look for it or there is none
+    pub semicolon: Option<usize>,
+}
+
+pub trait Scanner: Copy + Send + Sync {
+    /// Finds the first `\n` of `buf`, and the last `;` before it. `None` if there is no newline
+    fn find(self, buf: &[u8]) -> Option<Delimiters>;
+}
+
+/// Which scanner to use, from the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScanKind {
+    Bytewise,
+    /// The default, as it is a lot faster than bytewise while staying in safe Rust
+    #[default]
+    Swar,
+    /// The widest SIMD instructions the CPU supports, or SWAR on other architectures than x86_64
+    Simd,
+}
+impl ScanKind {
+    pub fn parse(s: &str) -> Option<ScanKind> {
+        match s {
+            "bytewise" => Some(ScanKind::Bytewise),
+            "swar" => Some(ScanKind::Swar),
+            "simd" => Some(ScanKind::Simd),
+            _ => None,
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScanKind::Bytewise => "bytewise",
+            ScanKind::Swar => "swar",
+            ScanKind::Simd => "simd",
+        }
+    }
+}
+
+/// The original scanner, only looks for the newline and leaves the `;` to `parse_line`
+#[derive(Clone, Copy)]
+pub struct Bytewise;
+impl Scanner for Bytewise {
+    #[inline]
+    fn find(self, buf: &[u8]) -> Option<Delimiters> {
+        buf.iter()
+            .position(|b| *b == b'\n')
+            .map(|newline| Delimiters {
+                newline,
+                semicolon: None,
+            })
+    }
+}
+
+const LO: u64 = 0x0101010101010101;
+const HI7: u64 = 0x7f7f7f7f7f7f7f7f;
+/// High bit set for every byte of `word` that equals `b`.
+/// Unlike the classic `(x - LO) & !x & HI` this has no false positives, so it can be used for the last match too
+#[inline]
+pub fn bytes_equal(word: u64, b: u8) -> u64 {
+    let x = word ^ (LO * b as u64);
+    !(((x & HI7) + HI7) | x | HI7)
+}
+
+/// SIMD within a register, 8 bytes at a time with plain `u64` arithmetic
+#[derive(Clone, Copy)]
+pub struct Swar;
+impl Scanner for Swar {
+    #[inline]
+    fn find(self, buf: &[u8]) -> Option<Delimiters> {
+        let mut semicolon = None;
+        let mut base = 0;
+        while base < buf.len() {
+            let word = if buf.len() - base >= 8 {
+                u64::from_le_bytes(buf[base..base + 8].try_into().unwrap())
+            } else {
+                // the tail is padded with zeros, which match neither of the delimiters
+                let mut bytes = [0; 8];
+                bytes[..buf.len() - base].copy_from_slice(&buf[base..]);
+                u64::from_le_bytes(bytes)
+            };
+            let newlines = bytes_equal(word, b'\n');
+            let semicolons = bytes_equal(word, b';');
+            if let Some(d) = delimiters_in_block(base, newlines, semicolons, 8, &mut semicolon) {
+                return Some(d);
+            }
+            base += 8;
+        }
+        None
+    }
+}
+
+/// Shared by all of the block based scanners. The masks have one bit per byte, `bits_per_byte` apart.
+/// Updates `semicolon` with the last `;` in the block if there was no newline
+#[inline]
+fn delimiters_in_block<M: Mask>(
+    base: usize,
+    newlines: M,
+    semicolons: M,
+    bits_per_byte: u32,
+    semicolon: &mut Option<usize>,
+) -> Option<Delimiters> {
+    if newlines.is_zero() {
+        if !semicolons.is_zero() {
+            *semicolon = Some(base + semicolons.last_bit() / bits_per_byte as usize);
+        }
+        return None;
+    }
+    let newline_bit = newlines.first_bit();
+    let before = semicolons.below(newline_bit);
+    if !before.is_zero() {
+        *semicolon = Some(base + before.last_bit() / bits_per_byte as usize);
+    }
+    Some(Delimiters {
+        newline: base + newline_bit / bits_per_byte as usize,
+        semicolon: *semicolon,
+    })
+}
+trait Mask: Copy {
+    fn is_zero(self) -> bool;
+    fn first_bit(self) -> usize;
+    fn last_bit(self) -> usize;
+    /// Only the bits below `bit`
+    fn below(self, bit: usize) -> Self;
+}
+macro_rules! impl_mask {
+    ($t:ty) => {
+        impl Mask for $t {
+            #[inline]
+            fn is_zero(self) -> bool {
+                self == 0
+            }
+            #[inline]
+            fn first_bit(self) -> usize {
+                self.trailing_zeros() as usize
+            }
+            #[inline]
+            fn last_bit(self) -> usize {
+                (<$t>::BITS - 1 - self.leading_zeros()) as usize
+            }
+            #[inline]
+            fn below(self, bit: usize) -> Self {
+                self & ((1 << bit) - 1)
+            }
+        }
+    };
+}
+impl_mask!(u32);
+impl_mask!(u64);
+
+#[cfg(target_arch = "x86_64")]
+pub use x86::{Avx2, Sse2};
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::{
+        __m128i, __m256i, _mm256_cmpeq_epi8, _mm256_loadu_si256, _mm256_movemask_epi8,
+        _mm256_set1_epi8, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8,
+    };
+
+    use super::{delimiters_in_block, Delimiters, Scanner, Swar};
+
+    /// 16 bytes at a time. SSE2 is part of the x86_64 baseline, so this is always available
+    #[derive(Clone, Copy)]
+    pub struct Sse2;
+    impl Scanner for Sse2 {
+        #[inline]
+        fn find(self, buf: &[u8]) -> Option<Delimiters> {
+            let mut semicolon = None;
+            let mut base = 0;
+            while buf.len() - base >= 16 {
+                let block = &buf[base..base + 16];
+                // SAFETY: SSE2 is always enabled on x86_64, and the unaligned load reads exactly the 16 bytes of `block`
+                let (newlines, semicolons) = unsafe {
+                    let v = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+                    (
+                        _mm_movemask_epi8(_mm_cmpeq_epi8(v, _mm_set1_epi8(b'\n' as i8))) as u32,
+                        _mm_movemask_epi8(_mm_cmpeq_epi8(v, _mm_set1_epi8(b';' as i8))) as u32,
+                    )
+                };
+                if let Some(d) = delimiters_in_block(base, newlines, semicolons, 1, &mut semicolon)
+                {
+                    return Some(d);
+                }
+                base += 16;
+            }
+            finish_with_swar(buf, base, semicolon)
+        }
+    }
+
+    /// 32 bytes at a time. Can only be constructed if the CPU supports AVX2
+    #[derive(Clone, Copy)]
+    pub struct Avx2 {
+        _detected: (),
+    }
+    impl Avx2 {
+        pub fn detect() -> Option<Avx2> {
+            is_x86_feature_detected!("avx2").then_some(Avx2 { _detected: () })
+        }
+    }
+    impl Scanner for Avx2 {
+        #[inline]
+        fn find(self, buf: &[u8]) -> Option<Delimiters> {
+            // SAFETY: an `Avx2` only exists if the CPU supports AVX2
+            unsafe { find_avx2(buf) }
+        }
+    }
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_avx2(buf: &[u8]) -> Option<Delimiters> {
+        let mut semicolon = None;
+        let mut base = 0;
+        while buf.len() - base >= 32 {
+            let block = &buf[base..base + 32];
+            // SAFETY: the unaligned load reads exactly the 32 bytes of `block`
+            let (newlines, semicolons) = unsafe {
+                let v = _mm256_loadu_si256(block.as_ptr() as *const __m256i);
+                (
+                    _mm256_movemask_epi8(_mm256_cmpeq_epi8(v, _mm256_set1_epi8(b'\n' as i8)))
+                        as u32,
+                    _mm256_movemask_epi8(_mm256_cmpeq_epi8(v, _mm256_set1_epi8(b';' as i8))) as u32,
+                )
+            };
+            if let Some(d) = delimiters_in_block(base, newlines, semicolons, 1, &mut semicolon) {
+                return Some(d);
+            }
+            base += 32;
+        }
+        finish_with_swar(buf, base, semicolon)
+    }
+    /// Scans the last bytes that do not fill a whole register
+    #[inline]
+    fn finish_with_swar(buf: &[u8], base: usize, semicolon: Option<usize>) -> Option<Delimiters> {
+        let mut d = Swar.find(&buf[base..])?;
+        d.newline += base;
+        d.semicolon = d.semicolon.map(|s| s + base).or(semicolon);
+        Some(d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bytewise, Delimiters, Scanner, Swar};
+
+    /// The expected result, done the slow way
+    fn reference(buf: &[u8]) -> Option<Delimiters> {
+        let newline = buf.iter().position(|b| *b == b'\n')?;
+        Some(Delimiters {
+            newline,
+            semicolon: buf[..newline].iter().rposition(|b| *b == b';'),
+        })
+    }
+    fn inputs() -> Vec<Vec<u8>> {
+        let mut inputs = vec![
+            b"".to_vec(),
+            b"\n".to_vec(),
+            b";\n".to_vec(),
+            b"a;1.0".to_vec(),
+            b"a;1.0\nb;2.0\n".to_vec(),
+            b"no separator\nb;2.0\n".to_vec(),
+            b"two;separators;-1.0\r\nrest".to_vec(),
+            "Ürümqi;-12.3\n".as_bytes().to_vec(),
+        ];
+        // delimiters at every offset around the 8, 16 and 32 byte block boundaries
+        for name_len in 0..80 {
+            let mut line = vec![b'x'; name_len];
+            line.extend(b";-99.9\nnext;1.0\n");
+            inputs.push(line.clone());
+            line.truncate(name_len + 6);
+            inputs.push(line);
+        }
+        // bytes with the high bit set must not be mistaken for delimiters
+        inputs.push([0x8a, 0xbb, 0xff, b';', 0x80, b'\n'].to_vec());
+        inputs
+    }
+    fn check(scanner: impl Scanner, finds_semicolon: bool) {
+        for input in inputs() {
+            let mut expected = reference(&input);
+            if !finds_semicolon {
+                expected.iter_mut().for_each(|d| d.semicolon = None);
+            }
+            assert_eq!(
+                scanner.find(&input),
+                expected,
+                "{:?}",
+                String::from_utf8_lossy(&input)
+            );
+        }
+    }
+
+    #[test]
+    fn bytewise_finds_newlines() {
+        check(Bytewise, false);
+    }
+    #[test]
+    fn swar_finds_delimiters() {
+        check(Swar, true);
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sse2_finds_delimiters() {
+        check(super::Sse2, true);
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_finds_delimiters() {
+        if let Some(avx2) = super::Avx2::detect() {
+            check(avx2, true);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Binary and JSON encoding of aggregated stations, keeping the sum and count so that the results can be combined later.
+//!
+//! In the binary encoding every integer is a little-endian `u64`/`i64` and names are length-prefixed bytes.
+//! Temperatures are in tenths of a degree in both, the same integers the aggregation uses.
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::WeatherStationStats;
+
+/// Start of a binary results file, JSON can never start with it
+const MAGIC: &[u8; 8] = b"BRCMAP1\n";
+
+pub fn write_u64(w: &mut impl Write, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+pub fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+/// Reads a count or a length, which has to fit in `usize` and be at most `max`, so a corrupted file
+/// fails with an error instead of trying to allocate a huge vector
+pub fn read_len(r: &mut impl Read, max: u64) -> io::Result<usize> {
+    match read_u64(r)? {
+        n if n <= max => Ok(n as usize),
+        n => Err(invalid_data(format!(
+            "length {n} is over the maximum of {max}"
+        ))),
+    }
+}
+pub fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Longest name accepted when reading, far more than any --max-name-len that fits in the read buffer
+const MAX_NAME_LEN: u64 = 1 << 32;
+
+pub fn write_stations<'a>(
+    w: &mut impl Write,
+    stations: impl ExactSizeIterator<Item = (&'a [u8], &'a WeatherStationStats)>,
+) -> io::Result<()> {
+    write_u64(w, stations.len() as u64)?;
+    for (name, stats) in stations {
+        write_u64(w, name.len() as u64)?;
+        w.write_all(name)?;
+        write_u64(w, stats.min as u64)?;
+        write_u64(w, stats.max as u64)?;
+        write_u64(w, stats.sum as u64)?;
+        write_u64(w, stats.count as u64)?;
+    }
+    Ok(())
+}
+pub fn read_stations(r: &mut impl Read) -> io::Result<Vec<(Vec<u8>, WeatherStationStats)>> {
+    let count = read_len(r, u32::MAX as u64)?;
+    // the count is not trusted for the allocation, a truncated file runs out of bytes long before
+    let mut stations = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        let mut name = vec![0; read_len(r, MAX_NAME_LEN)?];
+        r.read_exact(&mut name)?;
+        let stats = WeatherStationStats {
+            min: read_u64(r)? as i64,
+            max: read_u64(r)? as i64,
+            sum: read_u64(r)? as i64,
+            count: read_len(r, u64::MAX)?,
+        };
+        stations.push((name, stats));
+    }
+    Ok(stations)
+}
+
+pub fn write_binary(stations: &[(String, WeatherStationStats)]) -> Vec<u8> {
+    let mut res = MAGIC.to_vec();
+    write_stations(&mut res, stations.iter().map(|(k, v)| (k.as_bytes(), v))).unwrap();
+    res
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonResults {
+    stations: Vec<JsonStation>,
+}
+#[derive(Serialize, Deserialize)]
+struct JsonStation {
+    name: String,
+    min: i64,
+    max: i64,
+    sum: i64,
+    count: usize,
+}
+pub fn write_json(stations: &[(String, WeatherStationStats)]) -> Vec<u8> {
+    let results = JsonResults {
+        stations: stations
+            .iter()
+            .map(|(name, s)| JsonStation {
+                name: name.clone(),
+                min: s.min,
+                max: s.max,
+                sum: s.sum,
+                count: s.count,
+            })
+            .collect(),
+    };
+    let mut res = serde_json::to_vec(&results).unwrap();
+    res.push(b'\n');
+    res
+}
+
+/// Reads results written with either `write_binary` or `write_json`
+pub fn read_results(bytes: &[u8]) -> io::Result<Vec<(String, WeatherStationStats)>> {
+    if let Some(mut rest) = bytes.strip_prefix(MAGIC) {
+        let stations = read_stations(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(invalid_data("trailing bytes after the stations"));
+        }
+        return stations
+            .into_iter()
+            .map(|(name, stats)| {
+                String::from_utf8(name)
+                    .map(|name| (name, stats))
+                    .map_err(|_| invalid_data("station name is not valid UTF-8"))
+            })
+            .collect();
+    }
+    let results: JsonResults = serde_json::from_slice(bytes).map_err(io::Error::from)?;
+    Ok(results
+        .stations
+        .into_iter()
+        .map(|s| {
+            let stats = WeatherStationStats {
+                min: s.min,
+                max: s.max,
+                sum: s.sum,
+                count: s.count,
+            };
+            (s.name, stats)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_results, read_stations, write_binary, write_json, write_stations};
+    use crate::WeatherStationStats;
+
+    #[test]
+    fn stations_survive_a_round_trip() {
+        let stations = vec![
+            (
+                b"Hamburg".to_vec(),
+                WeatherStationStats {
+                    min: -999,
+                    max: 999,
+                    sum: -12_345_678_901,
+                    count: 4_000_000_000,
+                },
+            ),
+            (
+                vec![0xff, b';', b'\n'],
+                WeatherStationStats {
+                    min: 0,
+                    max: 0,
+                    sum: 0,
+                    count: 1,
+                },
+            ),
+        ];
+        let mut bytes = vec![];
+        write_stations(&mut bytes, stations.iter().map(|(k, v)| (&k[..], v))).unwrap();
+        assert_eq!(read_stations(&mut &bytes[..]).unwrap(), stations);
+        // every truncation is an error instead of a panic or a partial result
+        for len in 0..bytes.len() {
+            assert!(read_stations(&mut &bytes[..len]).is_err());
+        }
+    }
+    #[test]
+    fn results_survive_binary_and_json() {
+        let stations = vec![
+            (
+                String::from("Abéché"),
+                WeatherStationStats {
+                    min: -12,
+                    max: 345,
+                    sum: 9_876_543_210,
+                    count: 3,
+                },
+            ),
+            (
+                String::from("quote\" and \\x"),
+                WeatherStationStats {
+                    min: -999,
+                    max: -999,
+                    sum: -999,
+                    count: 1,
+                },
+            ),
+        ];
+        assert_eq!(read_results(&write_binary(&stations)).unwrap(), stations);
+        assert_eq!(read_results(&write_json(&stations)).unwrap(), stations);
+        assert!(read_results(b"{Abeche=1.0/1.0/1.0}").is_err());
+        let mut trailing = write_binary(&stations);
+        trailing.push(0);
+        assert!(read_results(&trailing).is_err());
+    }
+}
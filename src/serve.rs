@@ -0,0 +1,392 @@
+//! `brc-rs serve`: answers queries over the aggregated stations with a small JSON API over HTTP.
+//!
+//! The file is aggregated once, or followed like `brc-rs follow` with `--follow`, and every request reads the latest
+//! results. Only what the dashboards need of HTTP/1.1 is implemented: `GET` requests, one per connection.
+//!
+//! - `GET /health`: `{"status":"ok","stations":<count>,"rows":<count>,"skipped":<count>}`
+//! - `GET /stations`: every station sorted by name
+//! - `GET /stations/<name>`: one station, the name percent-encoded, 404 if there is no such station
+//! - `GET /top?by=min|mean|max|count&n=<count>&order=desc|asc`: the first `n` (10 by default) stations
+//!   ordered by `by` (`mean` by default), highest first unless `order=asc`
+//! - `GET /metrics`: the stations in the OpenMetrics text format, for Prometheus
+//!
+//! Stations are `{"name":..,"min":..,"mean":..,"max":..,"count":..}` with temperatures in degrees.
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    calc_stations, follow, openmetrics, stats::RunStats, Config, Error, WeatherStationStats,
+};
+
+/// Longest request head that is read, the API has no use for big headers
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// A client that does not send its request in time gets its connection closed
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TOP: usize = 10;
+const JSON: &str = "application/json";
+
+/// The results that a request is answered from
+#[derive(Default)]
+pub struct Snapshot {
+    /// Sorted by name
+    pub stations: Vec<(String, WeatherStationStats)>,
+    pub rows: usize,
+    /// Rows that were not added to the stations
+    pub skipped: usize,
+}
+
+#[derive(Serialize)]
+struct JsonStation<'a> {
+    name: &'a str,
+    min: f64,
+    mean: f64,
+    max: f64,
+    count: usize,
+}
+impl<'a> JsonStation<'a> {
+    fn new((name, stats): &'a (String, WeatherStationStats)) -> JsonStation<'a> {
+        JsonStation {
+            name,
+            min: stats.min as f64 / 10.0,
+            mean: stats.mean(),
+            max: stats.max as f64 / 10.0,
+            count: stats.count,
+        }
+    }
+}
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    stations: usize,
+    rows: usize,
+    skipped: usize,
+}
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error: &'a str,
+}
+
+/// Serves the results until the process is stopped
+pub fn serve(config: &Config) -> Result<Vec<u8>, Error> {
+    let listener = listen(config)?;
+    // replaced as a whole, so a request never waits for the results to be resolved and sorted
+    let latest = Arc::new(RwLock::new(Arc::new(Snapshot::default())));
+    let snapshot = {
+        let latest = latest.clone();
+        move || latest.read().unwrap().clone()
+    };
+    if !config.follow {
+        let aggregated = calc_stations(config, &mut RunStats::start())?;
+        *latest.write().unwrap() = Arc::new(Snapshot {
+            stations: aggregated.stations,
+            rows: aggregated.rows,
+            skipped: aggregated.skipped_rows,
+        });
+        accept(listener, snapshot);
+    }
+    thread::spawn(move || accept(listener, snapshot));
+    let mut follower = follow::Follower::new(config);
+    loop {
+        if follower.poll()? {
+            let stations = follower.stations(config)?;
+            *latest.write().unwrap() = Arc::new(Snapshot {
+                stations,
+                rows: follower.aggregated.rows,
+                skipped: follower.aggregated.skipped_rows,
+            });
+        }
+        thread::sleep(follow::POLL_INTERVAL);
+    }
+}
+/// Binds the address given with `--listen`
+pub fn listen(config: &Config) -> Result<TcpListener, Error> {
+    let listen_error = |error| Error::Listen {
+        address: config.listen.to_string(),
+        error,
+    };
+    let listener = TcpListener::bind(config.listen).map_err(listen_error)?;
+    eprintln!(
+        "listening on http://{}",
+        listener.local_addr().map_err(listen_error)?
+    );
+    Ok(listener)
+}
+/// Answers requests on `listener`, each from the results `snapshot` returns at the time
+pub fn accept(
+    listener: TcpListener,
+    snapshot: impl Fn() -> Arc<Snapshot> + Clone + Send + 'static,
+) -> ! {
+    loop {
+        let Ok(stream) = listener.accept().map(|(stream, _)| stream) else {
+            // the client went away before it was accepted, or too many files are open at the moment
+            continue;
+        };
+        let snapshot = snapshot.clone();
+        thread::spawn(move || {
+            // there is nobody to report to if the client has gone away
+            let _ = handle(stream, snapshot);
+        });
+    }
+}
+fn handle(mut stream: TcpStream, snapshot: impl Fn() -> Arc<Snapshot>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let (status, content_type, body) = match read_request_line(&mut stream)? {
+        Some(line) => respond(&line, &snapshot()),
+        None => error(400, "malformed request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+/// Reads the request head and returns its first line, `None` if it is not a complete request head
+fn read_request_line(stream: &mut impl Read) -> io::Result<Option<String>> {
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let line_len = head.windows(2).position(|w| w == b"\r\n").unwrap();
+    Ok(String::from_utf8(head[..line_len].to_vec()).ok())
+}
+/// Status code, content type and body for a request line such as `GET /top?n=5 HTTP/1.1`
+fn respond(request_line: &str, snapshot: &Snapshot) -> (u16, &'static str, Vec<u8>) {
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(_version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return error(400, "malformed request");
+    };
+    if method != "GET" {
+        return error(405, "only GET is supported");
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/health" => json(&Health {
+            status: "ok",
+            stations: snapshot.stations.len(),
+            rows: snapshot.rows,
+            skipped: snapshot.skipped,
+        }),
+        "/stations" => json(
+            &snapshot
+                .stations
+                .iter()
+                .map(JsonStation::new)
+                .collect::<Vec<_>>(),
+        ),
+        "/top" => top(query, snapshot),
+        "/metrics" => (
+            200,
+            openmetrics::CONTENT_TYPE,
+            openmetrics::render(&snapshot.stations).into_bytes(),
+        ),
+        _ => match path.strip_prefix("/stations/").map(percent_decode) {
+            Some(Some(name)) => match snapshot
+                .stations
+                .binary_search_by(|(station, _)| station.as_str().cmp(&name))
+            {
+                Ok(idx) => json(&JsonStation::new(&snapshot.stations[idx])),
+                Err(_) => error(404, "no such station"),
+            },
+            Some(None) => error(400, "station name is not valid percent-encoded UTF-8"),
+            None => error(404, "not found"),
+        },
+    }
+}
+fn top(query: &str, snapshot: &Snapshot) -> (u16, &'static str, Vec<u8>) {
+    let mut n = DEFAULT_TOP;
+    let mut by = "mean";
+    let mut ascending = false;
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        match param.split_once('=').unwrap_or((param, "")) {
+            ("n", value) => match value.parse() {
+                Ok(value) => n = value,
+                Err(_) => return error(400, "n expects a number"),
+            },
+            ("by", value @ ("min" | "mean" | "max" | "count")) => by = value,
+            ("by", _) => return error(400, "by expects one of: min, mean, max, count"),
+            ("order", "desc") => ascending = false,
+            ("order", "asc") => ascending = true,
+            ("order", _) => return error(400, "order expects one of: desc, asc"),
+            _ => return error(400, "unknown query parameter"),
+        }
+    }
+    let key = |stats: &WeatherStationStats| match by {
+        "min" => stats.min as f64,
+        "max" => stats.max as f64,
+        "count" => stats.count as f64,
+        _ => stats.mean(),
+    };
+    let mut stations = snapshot.stations.iter().collect::<Vec<_>>();
+    // stable, so stations with the same value stay sorted by name
+    stations.sort_by(|a, b| {
+        let order = key(&a.1).total_cmp(&key(&b.1));
+        if ascending {
+            order
+        } else {
+            order.reverse()
+        }
+    });
+    json(
+        &stations
+            .into_iter()
+            .take(n)
+            .map(JsonStation::new)
+            .collect::<Vec<_>>(),
+    )
+}
+fn json(value: &impl Serialize) -> (u16, &'static str, Vec<u8>) {
+    (200, JSON, serde_json::to_vec(value).unwrap())
+}
+fn error(status: u16, msg: &str) -> (u16, &'static str, Vec<u8>) {
+    (
+        status,
+        JSON,
+        serde_json::to_vec(&JsonError { error: msg }).unwrap(),
+    )
+}
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
+/// Decodes `%XX` escapes, `None` if an escape is broken or the result is not UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            res.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            res.push(b);
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+    };
+
+    use super::{respond, Snapshot};
+    use crate::{openmetrics, WeatherStationStats};
+
+    fn snapshot() -> Snapshot {
+        let stats = |min, max, sum, count| WeatherStationStats {
+            min,
+            max,
+            sum,
+            count,
+        };
+        Snapshot {
+            stations: vec![
+                ("Abha".into(), stats(-23, 251, 456, 4)),
+                ("São Paulo".into(), stats(150, 330, 480, 2)),
+                ("Zürich".into(), stats(-50, 120, 70, 2)),
+            ],
+            rows: 8,
+            skipped: 1,
+        }
+    }
+    fn get(target: &str) -> (u16, String) {
+        let (status, _, body) = respond(&format!("GET {target} HTTP/1.1"), &snapshot());
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn answers_queries() {
+        assert_eq!(
+            get("/health"),
+            (
+                200,
+                r#"{"status":"ok","stations":3,"rows":8,"skipped":1}"#.into()
+            )
+        );
+        let (status, body) = get("/stations");
+        assert_eq!(status, 200);
+        assert!(
+            body.starts_with(r#"[{"name":"Abha","min":-2.3,"mean":11.4,"max":25.1,"count":4},"#)
+        );
+        assert_eq!(
+            get("/stations/S%C3%A3o%20Paulo"),
+            (
+                200,
+                r#"{"name":"São Paulo","min":15.0,"mean":24.0,"max":33.0,"count":2}"#.into()
+            )
+        );
+        assert_eq!(get("/stations/Nowhere").0, 404);
+        assert_eq!(get("/stations/%C3").0, 400);
+
+        let names = |body: String| {
+            serde_json::from_str::<Vec<serde_json::Value>>(&body)
+                .unwrap()
+                .iter()
+                .map(|s| s["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(get("/top").1), ["São Paulo", "Abha", "Zürich"]);
+        assert_eq!(names(get("/top?by=min&n=2").1), ["São Paulo", "Abha"]);
+        assert_eq!(
+            names(get("/top?by=count&order=asc").1),
+            ["São Paulo", "Zürich", "Abha"]
+        );
+        assert_eq!(get("/top?by=median").0, 400);
+        assert_eq!(get("/top?n=-1").0, 400);
+        assert_eq!(get("/nothing").0, 404);
+        let (status, content_type, body) = respond("GET /metrics HTTP/1.1", &snapshot());
+        assert_eq!((status, content_type), (200, openmetrics::CONTENT_TYPE));
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .contains("brc_station_max_celsius{station=\"São Paulo\"} 33.0\n"));
+        assert_eq!(respond("POST /health HTTP/1.1", &snapshot()).0, 405);
+    }
+
+    #[test]
+    fn serves_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let snapshot = Arc::new(snapshot());
+        std::thread::spawn(move || super::accept(listener, move || snapshot.clone()));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /stations/Abha HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        let (head, body) = res.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(
+            body,
+            r#"{"name":"Abha","min":-2.3,"mean":11.4,"max":25.1,"count":4}"#
+        );
+    }
+}
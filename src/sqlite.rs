@@ -0,0 +1,157 @@
+//! `--sqlite`: keeps a history of the runs in a SQLite database, with the optional `sqlite` feature.
+//!
+//! Every run adds a row to `runs` and its stations to `results`, so aggregations can be compared across runs with SQL.
+//! The configuration is a JSON object with the options as they are given on the command line, for `json_extract`.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+use serde_json::json;
+
+use crate::{Aggregated, Config, WeatherStationStats};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    input TEXT NOT NULL,
+    input_bytes INTEGER NOT NULL,
+    rows INTEGER NOT NULL,
+    skipped_rows INTEGER NOT NULL,
+    -- UTC, as YYYY-MM-DD HH:MM:SS.SSS
+    started_at TEXT NOT NULL,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS results (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    station TEXT NOT NULL,
+    -- degrees, the mean is not rounded
+    min REAL NOT NULL,
+    mean REAL NOT NULL,
+    max REAL NOT NULL,
+    count INTEGER NOT NULL,
+    sum REAL NOT NULL,
+    PRIMARY KEY (run_id, station)
+);
+";
+
+/// Adds the run and its stations to the database at `path`, creating the tables if needed. Returns the id of the run
+pub fn export(
+    path: &str,
+    config: &Config,
+    started_at: SystemTime,
+    res: &Aggregated<Vec<(String, WeatherStationStats)>>,
+) -> rusqlite::Result<i64> {
+    let input = std::fs::canonicalize(&config.file_name)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| config.file_name.clone());
+    let input_bytes = std::fs::metadata(&config.file_name).map_or(0, |m| m.len());
+    let started_at = started_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut db = Connection::open(path)?;
+    db.execute_batch(SCHEMA)?;
+    // one transaction for the whole run, a half written run would be worse than none
+    let tx = db.transaction()?;
+    tx.execute(
+        "INSERT INTO runs (input, input_bytes, rows, skipped_rows, started_at, config)
+         VALUES (?1, ?2, ?3, ?4, datetime(?5, 'unixepoch', 'subsec'), ?6)",
+        params![
+            input,
+            input_bytes as i64,
+            res.rows as i64,
+            res.skipped_rows as i64,
+            started_at,
+            config_json(config),
+        ],
+    )?;
+    let run_id = tx.last_insert_rowid();
+    {
+        let mut insert = tx.prepare("INSERT INTO results VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+        for (station, stats) in &res.stations {
+            insert.execute(params![
+                run_id,
+                station,
+                stats.min as f64 / 10.0,
+                stats.mean(),
+                stats.max as f64 / 10.0,
+                stats.count as i64,
+                stats.sum as f64 / 10.0,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(run_id)
+}
+fn config_json(config: &Config) -> String {
+    json!({
+        "line-ending": config.line_ending.map_or("auto", |l| l.as_str()),
+        "invalid-utf8": config.invalid_utf8.as_str(),
+        "nfc": config.nfc,
+        "max-name-len": config.max_name_len,
+        "long-lines": config.long_lines.as_str(),
+        "threads": config.threads(),
+        "table": config.table.as_str(),
+        "scanner": config.scanner.as_str(),
+        "parser": config.parser.as_str(),
+        "reader": config.reader.as_str(),
+        "buffer-size": config.buf_len.map_or(json!("auto"), |n| json!(n)),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::{calc, Config};
+
+    #[test]
+    fn keeps_history_of_runs() {
+        let path = std::env::temp_dir().join(format!("brc-sqlite-{}.db", std::process::id()));
+        let db = path.display().to_string();
+        for (file_name, threads) in [
+            ("samples/measurements-3.txt", 1),
+            ("samples/measurements-10.txt", 2),
+        ] {
+            calc(&Config {
+                file_name: file_name.into(),
+                sqlite: Some(db.clone()),
+                threads: Some(threads.try_into().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let conn = Connection::open(&path).unwrap();
+        let runs = conn
+            .prepare(
+                "SELECT id, input, rows, json_extract(config, '$.threads'), started_at FROM runs ORDER BY id",
+            )
+            .unwrap()
+            .query_map([], |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, i64>(2)?,
+                    r.get::<_, i64>(3)?,
+                    r.get::<_, String>(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(runs.len(), 2);
+        assert!(runs[0].1.ends_with("samples/measurements-3.txt"));
+        assert_eq!((runs[0].2, runs[0].3), (6, 1));
+        assert_eq!(runs[1].3, 2);
+        assert_eq!(runs[1].4.len(), "YYYY-MM-DD HH:MM:SS.SSS".len());
+        let (min, mean, max, count): (f64, f64, f64, i64) = conn
+            .query_row(
+                "SELECT min, mean, max, count FROM results WHERE run_id = ?1 AND station = 'Bosaso'",
+                [runs[0].0],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((min, mean, max, count), (-15.0, 1.25, 20.0, 4));
+        std::fs::remove_file(path).unwrap();
+    }
+}
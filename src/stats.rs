@@ -0,0 +1,214 @@
+//! Statistics of a single run for `--stats`, to see where the time goes without reaching for `hyperfine` or `samply`.
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+impl StatsFormat {
+    pub fn parse(s: &str) -> Option<StatsFormat> {
+        match s {
+            "text" => Some(StatsFormat::Text),
+            "json" => Some(StatsFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WorkerStats {
+    pub rows: usize,
+    pub chunk_lens: Vec<usize>,
+    /// Wall time from the start of the worker until it ran out of chunks
+    pub parse_time: Duration,
+    /// Size of the map of the worker when it ran out of chunks, which is also its peak as nothing is ever removed
+    pub map_size: usize,
+}
+
+pub struct RunStats {
+    start: Instant,
+    phase_start: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    pub bytes: usize,
+    pub stations: usize,
+    pub workers: Vec<WorkerStats>,
+}
+impl RunStats {
+    pub fn start() -> RunStats {
+        let now = Instant::now();
+        RunStats {
+            start: now,
+            phase_start: now,
+            phases: vec![],
+            bytes: 0,
+            stations: 0,
+            workers: vec![],
+        }
+    }
+    /// Ends the current phase as `name`, the next phase starts right away
+    pub fn end_phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now - self.phase_start));
+        self.phase_start = now;
+    }
+    fn rows(&self) -> usize {
+        self.workers.iter().map(|w| w.rows).sum()
+    }
+    fn peak_map_size(&self) -> usize {
+        self.workers
+            .iter()
+            .map(|w| w.map_size)
+            .max()
+            .unwrap_or(0)
+            .max(self.stations)
+    }
+    pub fn render(&self, format: StatsFormat) -> String {
+        let total = self.phase_start - self.start;
+        let secs = total.as_secs_f64().max(f64::MIN_POSITIVE);
+        let bytes_per_sec = self.bytes as f64 / secs;
+        let rows_per_sec = self.rows() as f64 / secs;
+        let mut res = String::new();
+        match format {
+            StatsFormat::Text => {
+                for (name, time) in &self.phases {
+                    writeln!(res, "{name:<10} {:>10.3} ms", ms(*time)).unwrap();
+                }
+                writeln!(res, "{:<10} {:>10.3} ms", "total", ms(total)).unwrap();
+                writeln!(
+                    res,
+                    "{} bytes, {} rows: {:.1} MB/s, {:.2} M rows/s",
+                    self.bytes,
+                    self.rows(),
+                    bytes_per_sec / 1_000_000.0,
+                    rows_per_sec / 1_000_000.0
+                )
+                .unwrap();
+                writeln!(
+                    res,
+                    "{} stations, peak map size {}",
+                    self.stations,
+                    self.peak_map_size()
+                )
+                .unwrap();
+                writeln!(
+                    res,
+                    "{:<6} {:>12} {:>7} {:>14} {:>12} {:>12} {:>13} {:>9}",
+                    "worker",
+                    "rows",
+                    "chunks",
+                    "bytes",
+                    "min chunk",
+                    "max chunk",
+                    "parse ms",
+                    "map size"
+                )
+                .unwrap();
+                for (i, w) in self.workers.iter().enumerate() {
+                    writeln!(
+                        res,
+                        "{i:<6} {:>12} {:>7} {:>14} {:>12} {:>12} {:>13.3} {:>9}",
+                        w.rows,
+                        w.chunk_lens.len(),
+                        w.chunk_lens.iter().sum::<usize>(),
+                        w.chunk_lens.iter().min().unwrap_or(&0),
+                        w.chunk_lens.iter().max().unwrap_or(&0),
+                        ms(w.parse_time),
+                        w.map_size
+                    )
+                    .unwrap();
+                }
+            }
+            StatsFormat::Json => {
+                res.push_str("{\"phases_ms\":{");
+                for (i, (name, time)) in self.phases.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(res, "{sep}\"{name}\":{:.3}", ms(*time)).unwrap();
+                }
+                write!(
+                    res,
+                    "}},\"total_ms\":{:.3},\"bytes\":{},\"rows\":{},\"bytes_per_second\":{:.0},\"rows_per_second\":{:.0},\"stations\":{},\"peak_map_size\":{},\"workers\":[",
+                    ms(total),
+                    self.bytes,
+                    self.rows(),
+                    bytes_per_sec,
+                    rows_per_sec,
+                    self.stations,
+                    self.peak_map_size()
+                )
+                .unwrap();
+                for (i, w) in self.workers.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(
+                        res,
+                        "{sep}{{\"rows\":{},\"parse_ms\":{:.3},\"map_size\":{},\"chunk_lens\":{:?}}}",
+                        w.rows,
+                        ms(w.parse_time),
+                        w.map_size,
+                        w.chunk_lens
+                    )
+                    .unwrap();
+                }
+                res.push_str("]}\n");
+            }
+        }
+        res
+    }
+}
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RunStats, StatsFormat, WorkerStats};
+
+    fn stats() -> RunStats {
+        let mut stats = RunStats::start();
+        stats.end_phase("chunking");
+        stats.bytes = 100;
+        stats.stations = 3;
+        stats.workers = vec![
+            WorkerStats {
+                rows: 4,
+                chunk_lens: vec![40, 20],
+                map_size: 2,
+                ..Default::default()
+            },
+            WorkerStats {
+                rows: 3,
+                chunk_lens: vec![40],
+                map_size: 3,
+                ..Default::default()
+            },
+        ];
+        stats.end_phase("parse");
+        stats
+    }
+
+    #[test]
+    fn text_stats_have_every_worker() {
+        let text = stats().render(StatsFormat::Text);
+        assert!(text.starts_with("chunking"));
+        assert!(text.contains("100 bytes, 7 rows"));
+        assert!(text.contains("3 stations, peak map size 3"));
+        assert_eq!(text.lines().count(), 3 + 2 + 1 + 2);
+    }
+    #[test]
+    fn json_stats_have_every_field() {
+        let json = stats().render(StatsFormat::Json);
+        for field in [
+            "\"phases_ms\":{\"chunking\":",
+            ",\"parse\":",
+            "\"bytes\":100,\"rows\":7,",
+            "\"stations\":3,\"peak_map_size\":3,",
+            "{\"rows\":4,",
+            "\"chunk_lens\":[40, 20]}",
+        ] {
+            assert!(json.contains(field), "{field} missing from {json}");
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Branchless measurement parser working on the last 8 bytes of the line as a single `u64`.
+//!
+//! The word is loaded big-endian, so the fractional digit is the lowest byte, the `.` the second lowest and so on.
+//! The measurement is always `-?\d?\d\.\d`, so the only thing to figure out is how many bytes from the end the `;` is.
+use crate::scan::bytes_equal;
+
+/// Which measurement parser to use, from the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ParserKind {
+    /// `parse_line` and `parse_measurement`, matching the bytes one at a time
+    #[default]
+    Match,
+    Swar,
+}
+impl ParserKind {
+    pub fn parse(s: &str) -> Option<ParserKind> {
+        match s {
+            "match" => Some(ParserKind::Match),
+            "swar" => Some(ParserKind::Swar),
+            _ => None,
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ParserKind::Match => "match",
+            ParserKind::Swar => "swar",
+        }
+    }
+}
+
+/// The last 8 bytes of `bytes`, zero padded in front if there are less
+#[inline]
+fn load_tail(bytes: &[u8]) -> u64 {
+    let mut tail = [0; 8];
+    let n = bytes.len().min(8);
+    tail[8 - n..].copy_from_slice(&bytes[bytes.len() - n..]);
+    u64::from_be_bytes(tail)
+}
+
+/// `len` is the length of the measurement, 3 to 5 bytes
+#[inline]
+fn decode(word: u64, len: u32) -> i64 {
+    // the byte in front of the first digit, only the measurements with a sign have it inside the measurement
+    let is_negative = ((word >> ((len - 1) * 8)) & 0xff == b'-' as u64) as u64;
+    // the tens digit is there for "-12.3" and "12.3", but not "-1.2" or "1.2"
+    let has_tens = len as u64 - 3 - is_negative;
+    let fraction = word & 0x0f;
+    let ones = (word >> 16) & 0x0f;
+    let tens = ((word >> 24) & 0x0f) * has_tens;
+    let abs = (fraction + ones * 10 + tens * 100) as i64;
+    // same as `if is_negative { -abs } else { abs }`, without the branch
+    let sign = -(is_negative as i64);
+    (abs ^ sign) - sign
+}
+
+/// Same as `parse_line`
+#[inline]
+pub fn parse_line_swar(line: &[u8]) -> (&[u8], i64) {
+    let word = load_tail(line);
+    // the `;` is 3, 4 or 5 bytes from the end, take the one closest to the end like `parse_line` does
+    let semicolons = bytes_equal(word, b';') & 0x0000_8080_8000_0000;
+    if semicolons == 0 {
+        invalid(line);
+    }
+    let len = (semicolons.trailing_zeros() - 7) / 8;
+    (&line[..line.len() - len as usize - 1], decode(word, len))
+}
+
+/// Same as `parse_measurement`
+#[inline]
+pub fn parse_measurement_swar(value: &[u8]) -> i64 {
+    if !(3..=5).contains(&value.len()) {
+        invalid(value);
+    }
+    decode(load_tail(value), value.len() as u32)
+}
+
+#[cold]
+fn invalid(line: &[u8]) -> ! {
+    panic!("{:#?}", String::from_utf8_lossy(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line_swar, parse_measurement_swar};
+    use crate::{parse_line, parse_measurement};
+
+    /// Every possible measurement, with names of different lengths so that the line is sometimes shorter than 8 bytes
+    #[test]
+    fn swar_parser_matches_parse_line() {
+        let names = [
+            "",
+            "a",
+            "ab",
+            "Abha",
+            "St. John's",
+            "Ürümqi",
+            "a;b",
+            "ends with;",
+        ];
+        for m in -999..=999 {
+            let value = format!("{:.1}", m as f64 / 10.0);
+            for name in names {
+                let line = format!("{name};{value}");
+                let expected = parse_line(line.as_bytes());
+                assert_eq!(expected.1, m);
+                assert_eq!(parse_line_swar(line.as_bytes()), expected, "{line}");
+            }
+            assert_eq!(
+                parse_measurement_swar(value.as_bytes()),
+                parse_measurement(value.as_bytes()),
+                "{value}"
+            );
+        }
+    }
+    #[test]
+    #[should_panic]
+    fn swar_parser_panics_without_separator() {
+        parse_line_swar(b"abc12.3");
+    }
+}
@@ -0,0 +1,358 @@
+//! The tables the workers aggregate the measurements into.
+//!
+//! `StationMap` is the std `HashMap` with the custom hasher, `OpenTable` is a purpose-built open-addressing table.
+//! Both are behind `StationTable`, so the aggregation does not care which one is used and they can be benchmarked
+//! against each other with `--table`.
+//!
+//! `OpenTable` stores names up to 16 bytes inline as two words, and compares the hash, the length and those words
+//! before the rest of a longer name. It hashes the two words loaded from the name slice it is given, and the tail of
+//! longer names. It starts with room for the 10 000 stations of the challenge at a low load factor, and doubles before
+//! it gets half full, as `follow` and `ingest` can see any number of stations.
+use crate::{StationMap, WeatherStationStats};
+
+pub trait StationTable: Default + Send {
+    fn add_measurement(&mut self, station_name: &[u8], measurement: i64);
+    /// Adds stats that were already aggregated elsewhere, used when merging the tables of the workers
+    fn add_stats(&mut self, station_name: Vec<u8>, stats: WeatherStationStats);
+    fn len(&self) -> usize;
+    fn into_stations(self) -> impl Iterator<Item = (Vec<u8>, WeatherStationStats)>;
+    /// Copies the stations while the worker keeps adding to the table, used for checkpoints
+    fn snapshot(&self) -> Vec<(Vec<u8>, WeatherStationStats)>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TableKind {
+    #[default]
+    Std,
+    Open,
+}
+impl TableKind {
+    pub fn parse(s: &str) -> Option<TableKind> {
+        match s {
+            "std" => Some(TableKind::Std),
+            "open" => Some(TableKind::Open),
+            _ => None,
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TableKind::Std => "std",
+            TableKind::Open => "open",
+        }
+    }
+}
+
+impl StationTable for StationMap {
+    #[inline]
+    fn add_measurement(&mut self, station_name: &[u8], measurement: i64) {
+        match self.get_mut(station_name) {
+            None => {
+                self.insert(
+                    station_name.to_vec(),
+                    WeatherStationStats {
+                        min: measurement,
+                        max: measurement,
+                        sum: measurement,
+                        count: 1,
+                    },
+                );
+            }
+            Some(s) => {
+                s.max = s.max.max(measurement);
+                s.min = s.min.min(measurement);
+                s.count += 1;
+                s.sum += measurement;
+            }
+        };
+    }
+    fn add_stats(&mut self, station_name: Vec<u8>, stats: WeatherStationStats) {
+        match self.get_mut(&station_name) {
+            Some(jutska) => *jutska = stats + jutska,
+            None => {
+                self.insert(station_name, stats);
+            }
+        }
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+    fn into_stations(self) -> impl Iterator<Item = (Vec<u8>, WeatherStationStats)> {
+        self.into_iter()
+    }
+    fn snapshot(&self) -> Vec<(Vec<u8>, WeatherStationStats)> {
+        self.iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+}
+
+/// Names up to this length are stored inline in the entry, longer ones store the rest in `Entry::tail`
+const INLINE_LEN: usize = 16;
+/// The challenge has at most 10 000 unique stations, which keeps the load factor at ~30%.
+/// If there are more, the table doubles in size before it gets half full
+const DEFAULT_CAPACITY: usize = 1 << 15;
+
+struct Entry {
+    hash: u64,
+    len: usize,
+    /// First `INLINE_LEN` bytes of the name, zero padded
+    prefix: [u64; 2],
+    /// Bytes after the prefix, empty (and not allocated) for short names
+    tail: Box<[u8]>,
+    stats: WeatherStationStats,
+}
+impl Entry {
+    #[inline]
+    fn is(&self, hash: u64, prefix: [u64; 2], station_name: &[u8]) -> bool {
+        // hash, length and the prefix words are all compared before touching the tail,
+        // so most lookups never compare more than two words of the name
+        self.hash == hash
+            && self.len == station_name.len()
+            && self.prefix == prefix
+            && *self.tail == station_name[station_name.len().min(INLINE_LEN)..]
+    }
+    fn name(&self) -> Vec<u8> {
+        let mut name = Vec::with_capacity(self.len);
+        name.extend(self.prefix[0].to_le_bytes());
+        name.extend(self.prefix[1].to_le_bytes());
+        name.truncate(self.len.min(INLINE_LEN));
+        name.extend_from_slice(&self.tail);
+        name
+    }
+}
+
+/// Open-addressing table with linear probing, the hash is taken from the prefix words, the tail and the length of the name
+pub struct OpenTable {
+    slots: Vec<Option<Entry>>,
+    len: usize,
+    /// Slot index is taken from the highest bits of the hash, as the low bits of the multiplication are the weakest
+    shift: u32,
+}
+impl Default for OpenTable {
+    fn default() -> Self {
+        OpenTable::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+impl OpenTable {
+    fn with_capacity(capacity: usize) -> OpenTable {
+        let capacity = capacity.next_power_of_two();
+        OpenTable {
+            slots: (0..capacity).map(|_| None).collect(),
+            len: 0,
+            shift: 64 - capacity.trailing_zeros(),
+        }
+    }
+    /// Index of the entry for the name, or the empty slot where it should be inserted
+    #[inline]
+    fn find(&self, hash: u64, prefix: [u64; 2], station_name: &[u8]) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut idx = (hash >> self.shift) as usize;
+        loop {
+            match &self.slots[idx] {
+                Some(e) if !e.is(hash, prefix, station_name) => idx = (idx + 1) & mask,
+                _ => return idx,
+            }
+        }
+    }
+    #[cold]
+    fn insert_new(
+        &mut self,
+        idx: usize,
+        hash: u64,
+        prefix: [u64; 2],
+        station_name: &[u8],
+        stats: WeatherStationStats,
+    ) {
+        self.slots[idx] = Some(Entry {
+            hash,
+            len: station_name.len(),
+            prefix,
+            tail: station_name[station_name.len().min(INLINE_LEN)..].into(),
+            stats,
+        });
+        self.len += 1;
+        if self.len * 2 > self.slots.len() {
+            self.grow();
+        }
+    }
+    #[cold]
+    fn grow(&mut self) {
+        let old = std::mem::replace(self, OpenTable::with_capacity(self.slots.len() * 2));
+        for e in old.slots.into_iter().flatten() {
+            let mask = self.slots.len() - 1;
+            let mut idx = (e.hash >> self.shift) as usize;
+            while self.slots[idx].is_some() {
+                idx = (idx + 1) & mask;
+            }
+            self.slots[idx] = Some(e);
+            self.len += 1;
+        }
+    }
+}
+#[inline]
+fn load_prefix(station_name: &[u8]) -> [u64; 2] {
+    let mut bytes = [0; INLINE_LEN];
+    let n = station_name.len().min(INLINE_LEN);
+    bytes[..n].copy_from_slice(&station_name[..n]);
+    [
+        u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+        u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+    ]
+}
+const K: u64 = 0x517cc1b727220a95;
+/// Same mixing as `CustomHasher`, a word at a time: the two prefix words, the tail of long names and the length.
+/// The tail is needed, names sharing their first `INLINE_LEN` bytes and length would all end up in one probe chain
+#[inline]
+fn hash(prefix: [u64; 2], station_name: &[u8]) -> u64 {
+    let mut h = prefix[0].wrapping_mul(K);
+    h = (h.rotate_left(5) ^ prefix[1]).wrapping_mul(K);
+    if station_name.len() > INLINE_LEN {
+        h = hash_tail(h, &station_name[INLINE_LEN..]);
+    }
+    (h.rotate_left(5) ^ station_name.len() as u64).wrapping_mul(K)
+}
+fn hash_tail(mut h: u64, tail: &[u8]) -> u64 {
+    for word in tail.chunks(8) {
+        let mut bytes = [0; 8];
+        bytes[..word.len()].copy_from_slice(word);
+        h = (h.rotate_left(5) ^ u64::from_le_bytes(bytes)).wrapping_mul(K);
+    }
+    h
+}
+impl StationTable for OpenTable {
+    #[inline]
+    fn add_measurement(&mut self, station_name: &[u8], measurement: i64) {
+        let prefix = load_prefix(station_name);
+        let hash = hash(prefix, station_name);
+        let idx = self.find(hash, prefix, station_name);
+        match &mut self.slots[idx] {
+            Some(e) => {
+                let s = &mut e.stats;
+                s.max = s.max.max(measurement);
+                s.min = s.min.min(measurement);
+                s.count += 1;
+                s.sum += measurement;
+            }
+            None => self.insert_new(
+                idx,
+                hash,
+                prefix,
+                station_name,
+                WeatherStationStats {
+                    min: measurement,
+                    max: measurement,
+                    sum: measurement,
+                    count: 1,
+                },
+            ),
+        }
+    }
+    fn add_stats(&mut self, station_name: Vec<u8>, stats: WeatherStationStats) {
+        let prefix = load_prefix(&station_name);
+        let hash = hash(prefix, &station_name);
+        let idx = self.find(hash, prefix, &station_name);
+        match &mut self.slots[idx] {
+            Some(e) => e.stats = stats + &mut e.stats,
+            None => self.insert_new(idx, hash, prefix, &station_name, stats),
+        }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn into_stations(self) -> impl Iterator<Item = (Vec<u8>, WeatherStationStats)> {
+        self.slots
+            .into_iter()
+            .flatten()
+            .map(|e| (e.name(), e.stats))
+    }
+    fn snapshot(&self) -> Vec<(Vec<u8>, WeatherStationStats)> {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|e| (e.name(), e.stats))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, load_prefix, OpenTable, StationTable};
+    use crate::StationMap;
+
+    fn stations(table: impl StationTable) -> Vec<(Vec<u8>, i64, i64, i64, usize)> {
+        let mut res = table
+            .into_stations()
+            .map(|(k, v)| (k, v.min, v.max, v.sum, v.count))
+            .collect::<Vec<_>>();
+        res.sort_unstable();
+        res
+    }
+    fn names() -> Vec<Vec<u8>> {
+        let mut names = vec![
+            b"".to_vec(),
+            b"a".to_vec(),
+            // same length and same first 16 bytes, only the tail differs
+            b"Washington, D.C. 1".to_vec(),
+            b"Washington, D.C. 2".to_vec(),
+            b"exactly16bytes!!".to_vec(),
+            "Ürümqi".as_bytes().to_vec(),
+            vec![b'x'; 100],
+        ];
+        names.extend((0..10_000).map(|i| format!("id{i}").into_bytes()));
+        names
+    }
+
+    #[test]
+    fn open_table_matches_std_map() {
+        let mut open = OpenTable::default();
+        let mut std = StationMap::default();
+        for (i, name) in names().iter().cycle().take(50_000).enumerate() {
+            let measurement = (i as i64 * 37) % 1999 - 999;
+            open.add_measurement(name, measurement);
+            std.add_measurement(name, measurement);
+        }
+        assert_eq!(StationTable::len(&open), StationTable::len(&std));
+        assert_eq!(stations(open), stations(std));
+    }
+    #[test]
+    fn open_table_grows_past_default_capacity() {
+        let mut open = OpenTable::with_capacity(16);
+        let mut std = StationMap::default();
+        for (i, name) in names().iter().enumerate() {
+            open.add_measurement(name, i as i64);
+            std.add_measurement(name, i as i64);
+        }
+        assert!(open.slots.len() > 16);
+        assert_eq!(stations(open), stations(std));
+    }
+    #[test]
+    fn tail_spreads_names_with_a_shared_prefix() {
+        let table = OpenTable::default();
+        let mut slots = (0..1000)
+            .map(|i| {
+                let name = format!("Washington, D.C. {i:04}");
+                (hash(load_prefix(name.as_bytes()), name.as_bytes()) >> table.shift) as usize
+            })
+            .collect::<Vec<_>>();
+        slots.sort_unstable();
+        slots.dedup();
+        // 1000 names in 32768 slots, only a few should share their first slot
+        assert!(slots.len() > 950, "{}", slots.len());
+    }
+    #[test]
+    fn open_table_merges_stats() {
+        let mut a = OpenTable::default();
+        let mut b = OpenTable::default();
+        for name in names() {
+            a.add_measurement(&name, -10);
+            b.add_measurement(&name, 20);
+        }
+        for (k, v) in b.into_stations() {
+            a.add_stats(k, v);
+        }
+        let res = stations(a);
+        assert_eq!(res.len(), names().len());
+        assert!(res
+            .iter()
+            .all(|(_, min, max, sum, count)| (*min, *max, *sum, *count) == (-10, 20, 10, 2)));
+    }
+}
@@ -0,0 +1,171 @@
+//! The challenge output `{Abha=-23.0/18.0/59.2, Abidjan=-16.2/26.0/67.3, ...}`, written and read back.
+//!
+//! Names are written as they are, so they can have `, `, `=` and `/` in them, like `Washington, D.C.`. When reading,
+//! a name ends at the first `=` that is followed by exactly three numbers with one decimal separated by `/` and then
+//! `, ` or the closing brace. Only a name with such a suffix inside it, like `a=1.0/1.0/1.0, b`, is read differently
+//! from how it was written.
+use std::{fmt::Write, io};
+
+use crate::{serialize::invalid_data, WeatherStationStats};
+
+/// The stats of a station as written, in tenths of a degree. The mean is rounded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextStats {
+    pub min: i64,
+    pub mean: i64,
+    pub max: i64,
+}
+
+/// `stations` must be sorted by name
+pub fn write(stations: &[(String, WeatherStationStats)]) -> String {
+    let mut res = String::from("{");
+    for (i, (station, stats)) in stations.iter().enumerate() {
+        if i > 0 {
+            res.push_str(", ");
+        }
+        write!(
+            res,
+            "{}={:.1}/{:.1}/{:.1}",
+            station,
+            stats.min as f64 / 10.0,
+            stats.mean(),
+            stats.max as f64 / 10.0
+        )
+        .unwrap();
+    }
+    res.push_str("}\n");
+    res
+}
+
+/// Reads the stations written with `write`, in the order they are in
+pub fn parse(bytes: &[u8]) -> io::Result<Vec<(String, TextStats)>> {
+    let text =
+        std::str::from_utf8(bytes).map_err(|_| invalid_data("text results are not valid UTF-8"))?;
+    let Some(mut rest) = text
+        .trim_end_matches(['\r', '\n'])
+        .strip_prefix('{')
+        .and_then(|t| t.strip_suffix('}'))
+    else {
+        return Err(invalid_data("text results are not in braces"));
+    };
+    let mut stations = vec![];
+    while !rest.is_empty() {
+        let Some((name_len, stats, stats_len)) = rest.match_indices('=').find_map(|(i, _)| {
+            let (stats, len) = stats_at(&rest[i + 1..])?;
+            let after = &rest[i + 1 + len..];
+            // a separator has to be followed by another station
+            (after.is_empty() || after.len() > 2 && after.starts_with(", "))
+                .then_some((i, stats, len))
+        }) else {
+            let start = rest.chars().take(32).collect::<String>();
+            return Err(invalid_data(format!(
+                "no min/mean/max after \"{start}\" at byte {}",
+                // the offset in the input, after the opening brace
+                text.len() - rest.len()
+            )));
+        };
+        stations.push((rest[..name_len].to_string(), stats));
+        rest = &rest[name_len + 1 + stats_len..];
+        rest = rest.strip_prefix(", ").unwrap_or(rest);
+    }
+    Ok(stations)
+}
+/// `min/mean/max` at the start of `s` and its length
+fn stats_at(s: &str) -> Option<(TextStats, usize)> {
+    let (min, min_len) = tenths_at(s)?;
+    let s = s[min_len..].strip_prefix('/')?;
+    let (mean, mean_len) = tenths_at(s)?;
+    let s = s[mean_len..].strip_prefix('/')?;
+    let (max, max_len) = tenths_at(s)?;
+    Some((
+        TextStats { min, mean, max },
+        min_len + mean_len + max_len + 2,
+    ))
+}
+/// A number with exactly one decimal at the start of `s` in tenths, and its length
+fn tenths_at(s: &str) -> Option<(i64, usize)> {
+    let bytes = s.as_bytes();
+    let negative = bytes.first() == Some(&b'-');
+    let start = negative as usize;
+    let whole_len = bytes[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    let dot = start + whole_len;
+    if whole_len == 0 || bytes.get(dot) != Some(&b'.') || !bytes.get(dot + 1)?.is_ascii_digit() {
+        return None;
+    }
+    let len = dot + 2;
+    if bytes.get(len).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    let tenths = s[start..dot].parse::<i64>().ok()? * 10 + (bytes[dot + 1] - b'0') as i64;
+    Some((if negative { -tenths } else { tenths }, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, write, TextStats};
+    use crate::WeatherStationStats;
+
+    #[test]
+    fn parses_names_with_separators() {
+        let stats = |min, sum, max| WeatherStationStats {
+            min,
+            max,
+            sum,
+            count: 1,
+        };
+        let stations = [
+            ("".to_string(), stats(0, 0, 0)),
+            ("a=b".to_string(), stats(-999, -999, -999)),
+            ("Washington, D.C.".to_string(), stats(-5, -5, -5)),
+            ("x=1.0/2.0, y".to_string(), stats(10, 10, 10)),
+            ("z=1.0/2.0/3.00, w".to_string(), stats(999, 999, 999)),
+        ];
+        let text = write(&stations);
+        assert_eq!(
+            text,
+            "{=0.0/0.0/0.0, a=b=-99.9/-99.9/-99.9, Washington, D.C.=-0.5/-0.5/-0.5, \
+             x=1.0/2.0, y=1.0/1.0/1.0, z=1.0/2.0/3.00, w=99.9/99.9/99.9}\n"
+        );
+        let parsed = parse(text.as_bytes()).unwrap();
+        assert_eq!(
+            parsed
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            stations
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            parsed[2].1,
+            TextStats {
+                min: -5,
+                mean: -5,
+                max: -5
+            }
+        );
+        assert_eq!(parse(b"{}\r\n").unwrap(), []);
+        // without the space after the comma it is all one name
+        let parsed = parse(b"{Abha=1.0/1.0/1.0,Oslo=2.0/2.0/2.0}").unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].0, "Abha=1.0/1.0/1.0,Oslo");
+        for invalid in [
+            &b"Abha=1.0/1.0/1.0"[..],
+            b"{Abha=1.0/1.0}",
+            b"{Abha=1.0/1.0/1.0, }",
+            b"{Abha=1.0/1.0/1.0 , Oslo}",
+            b"{Abha=1/1/1}",
+            b"{Abha=\xff/1.0/1.0}",
+        ] {
+            assert!(
+                parse(invalid).is_err(),
+                "{}",
+                String::from_utf8_lossy(invalid)
+            );
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! `brc-rs tune`: runs the whole calculation with several read buffer sizes and recommends the fastest.
+use std::{fmt::Write, time::Instant};
+
+use crate::{buffer, calc, output::OutputFormat, Config, Error};
+
+const CANDIDATES: [usize; 7] = [
+    64 * 1024,
+    128 * 1024,
+    256 * 1024,
+    512 * 1024,
+    1024 * 1024,
+    2 * 1024 * 1024,
+    4 * 1024 * 1024,
+];
+/// The best of a few runs, as the first run of a size can be slowed down by the page cache or frequency scaling
+const ROUNDS: usize = 3;
+
+pub fn tune(config: &Config) -> Result<String, Error> {
+    // every run has to do the same work, and leave nothing behind: with the --state index only the first run
+    // would read the file, and a checkpoint of the first run would fail the next
+    let config = Config {
+        stats: None,
+        progress: false,
+        checkpoint: None,
+        resume: false,
+        state: None,
+        sqlite: None,
+        format: OutputFormat::Text,
+        ..config.clone()
+    };
+    // warm up the page cache, so the first candidate is not the only one reading from the disk
+    calc(&config)?;
+    let mut res = format!("buffer size  best of {ROUNDS}\n");
+    let mut best = None;
+    for buf_len in CANDIDATES {
+        if buf_len < config.max_name_len + 8 {
+            continue;
+        }
+        let config = Config {
+            buf_len: Some(buf_len),
+            ..config.clone()
+        };
+        let mut fastest = f64::MAX;
+        for _ in 0..ROUNDS {
+            let start = Instant::now();
+            calc(&config)?;
+            fastest = fastest.min(start.elapsed().as_secs_f64());
+        }
+        writeln!(res, "{buf_len:>11}  {fastest:.3} s").unwrap();
+        if best.is_none_or(|(_, time)| fastest < time) {
+            best = Some((buf_len, fastest));
+        }
+    }
+    let file_len = std::fs::metadata(&config.file_name).unwrap().len() as usize;
+    let auto = buffer::auto_len(file_len, config.threads(), buffer::page_size());
+    if let Some((buf_len, _)) = best {
+        write!(
+            res,
+            "recommended: --buffer-size {buf_len} (auto picks {auto} for this file)"
+        )
+        .unwrap();
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tune;
+    use crate::Config;
+
+    #[test]
+    fn ignores_options_that_keep_state() {
+        let dir = std::env::temp_dir();
+        let state = dir.join(format!("brc-tune-state-{}", std::process::id()));
+        let checkpoint = dir.join(format!("brc-tune-checkpoint-{}", std::process::id()));
+        let res = tune(&Config {
+            file_name: "samples/measurements-3.txt".into(),
+            state: Some(state.display().to_string()),
+            checkpoint: Some(checkpoint.display().to_string()),
+            resume: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(res.contains("recommended: --buffer-size"));
+        assert!(!state.exists());
+        assert!(!checkpoint.exists());
+    }
+}